@@ -0,0 +1,66 @@
+use std::fmt;
+
+use wasm_bindgen::prelude::*;
+
+/// Location of a piece of code.
+/// Offsets and columns count characters, lines and columns start at 1.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start : usize,
+    pub end : usize,
+    pub line : usize,
+    pub column : usize,
+}
+
+impl Span {
+    /// Span going from the start of `self` to the end of `other`.
+    pub(crate) fn to(self, other : Span) -> Span {
+        Span { end : other.end, ..self }
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Problem found in the code, with where it is and how to fix it.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub message : String,
+    pub span : Span,
+    pub severity : Severity,
+    pub hint : Option<String>,
+}
+
+impl Diagnostic {
+    pub(crate) fn error(message : String, span : Span) -> Diagnostic {
+        Diagnostic { message, span, severity : Severity::Error, hint : None }
+    }
+
+    pub(crate) fn with_hint(self, hint : &str) -> Diagnostic {
+        Diagnostic { hint : Some(hint.to_string()), ..self }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// All the diagnostics in a single message, one per line.
+pub(crate) fn to_message(diagnostics : &[Diagnostic]) -> String {
+    diagnostics.iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
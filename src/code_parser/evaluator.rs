@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use super::diagnostic::{Diagnostic, Span};
+use super::parser::*;
+use super::resolver::Function;
+
+/// Number of calls that can be nested, a function calling itself having no
+/// way to stop
+const MAX_DEPTH : usize = 32;
+/// Number of notes the calls can expand to
+const MAX_EXPANSION : usize = 100_000;
+
+/// State of the expansion of the calls.
+struct Evaluator<'a> {
+    functions : &'a HashMap<String, Function>,
+    /// Notes produced by the calls so far
+    expansion : usize,
+}
+
+/// Values of the parameters of the function being expanded
+type Env = HashMap<String, i32>;
+
+/// Replaces the calls by the bodies of their functions and computes the
+/// pitches written as expressions. A block whose expansion fails is reported
+/// and left out.
+pub fn evaluate(axiom : &mut Axiom, functions : &HashMap<String, Function>, diagnostics : &mut Vec<Diagnostic>) {
+    let mut evaluator = Evaluator { functions, expansion : 0 };
+    let blocks = std::mem::take(&mut axiom.blocks);
+    axiom.blocks = evaluator.top_level_blocks(blocks, diagnostics);
+}
+
+impl<'a> Evaluator<'a> {
+    /// Blocks written in the code, recovered from one by one so that a single
+    /// faulty call is reported once.
+    fn top_level_blocks(&mut self, blocks : Vec<Block>, diagnostics : &mut Vec<Diagnostic>) -> Vec<Block> {
+        let mut evaluated = Vec::new();
+        for block in blocks {
+            let result = match block {
+                Block::Recursive(block) => Ok(Block::Recursive(RecBlock {
+                    filters : block.filters,
+                    blocks : self.top_level_blocks(block.blocks, diagnostics),
+                })),
+                Block::Section(section) => Ok(Block::Section(Section {
+                    blocks : self.top_level_blocks(section.blocks, diagnostics),
+                    ..section
+                })),
+                block => self.block(block, &Env::new(), 0),
+            };
+            match result {
+                Ok(block) => evaluated.push(block),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
+
+        evaluated
+    }
+
+    fn block(&mut self, block : Block, env : &Env, depth : usize) -> Result<Block, Diagnostic> {
+        Ok(match block {
+            Block::Recursive(block) => Block::Recursive(RecBlock {
+                filters : block.filters,
+                blocks : block.blocks.into_iter().map(|block| self.block(block, env, depth)).collect::<Result<_, _>>()?,
+            }),
+            Block::Instrument(instrument) => {
+                self.expand(note_count(&instrument.notes), instrument.span)?;
+                Block::Instrument(Instrument {
+                    notes : self.notes(instrument.notes, env, depth)?,
+                    ..instrument
+                })
+            },
+            Block::Call(call) => {
+                let (function, env) = self.enter(&call, env, depth)?;
+                match &function.body {
+                    LetValue::Block(body) => self.block((**body).clone(), &env, depth + 1)?,
+                    LetValue::Notes(_) => return Err(returns(&call, "a note list", "a block")),
+                    LetValue::Filters(_) => return Err(returns(&call, "a filter chain", "a block")),
+                }
+            },
+            Block::Let(Let {span, ..}) | Block::Def(Def {span, ..}) | Block::Reference(Reference {span, ..}) =>
+                return Err(Diagnostic::error("Unresolved name".to_string(), span)),
+            Block::Section(section) => return Err(Diagnostic::error("Sections are written at the top level".to_string(), section.span)),
+        })
+    }
+
+    fn notes(&mut self, notes : Vec<Note>, env : &Env, depth : usize) -> Result<Vec<Note>, Diagnostic> {
+        let mut evaluated = Vec::new();
+        for note in notes {
+            match note.kind {
+                NoteKind::Expr(expr) => evaluated.push(Note {kind : NoteKind::Pitch(Pitch(eval(&expr, env)?)), ..note}),
+                NoteKind::Group(children) => evaluated.push(Note {kind : NoteKind::Group(self.notes(children, env, depth)?), ..note}),
+                NoteKind::Maybe(maybe, probability) => {
+                    let maybe = Note::single(self.notes(vec![*maybe], env, depth)?);
+                    evaluated.push(Note {kind : NoteKind::Maybe(Box::new(maybe), probability), ..note});
+                },
+                // Drawn by the builder, in each bar
+                NoteKind::Random(low, high) => {
+                    let kind = NoteKind::Random(Expr::Value(eval(&low, env)?), Expr::Value(eval(&high, env)?));
+                    evaluated.push(Note {kind, ..note});
+                },
+                NoteKind::Euclid(euclid) => {
+                    let kind = NoteKind::Euclid(self.euclid(euclid, env, depth)?);
+                    evaluated.push(Note {kind, ..note});
+                },
+                NoteKind::Alternation(alternatives) => {
+                    let alternatives = alternatives.into_iter()
+                        .map(|alternative| self.notes(vec![alternative], env, depth).map(Note::single))
+                        .collect::<Result<_, _>>()?;
+                    evaluated.push(Note {kind : NoteKind::Alternation(alternatives), ..note});
+                },
+                NoteKind::Call(call) => {
+                    let (function, env) = self.enter(&call, env, depth)?;
+                    let body = match &function.body {
+                        LetValue::Notes(body) => self.notes(body.clone(), &env, depth + 1)?,
+                        LetValue::Block(_) => return Err(returns(&call, "a block", "a note list")
+                            .with_hint("Functions returning a block are called on their own line, like an instrument")),
+                        LetValue::Filters(_) => return Err(returns(&call, "a filter chain", "a note list")),
+                    };
+                    self.expand(body.len(), call.span)?;
+                    if note.weight == 1 {
+                        evaluated.extend(body);
+                    }
+                    else {
+                        evaluated.push(Note {kind : NoteKind::Group(body), weight : note.weight});
+                    }
+                },
+                _ => evaluated.push(note),
+            }
+        }
+
+        Ok(evaluated)
+    }
+
+    /// Computes the arguments of the rhythm, which must describe one.
+    fn euclid(&mut self, euclid : Euclid, env : &Env, depth : usize) -> Result<Euclid, Diagnostic> {
+        let (hits, steps) = (eval(&euclid.hits, env)?, eval(&euclid.steps, env)?);
+        if steps < 1 {
+            return Err(Diagnostic::error("A euclidean rhythm has at least one step".to_string(), euclid.span));
+        }
+        if !(0..=steps).contains(&hits) {
+            return Err(Diagnostic::error(format!("Cannot place {} hits on {} steps", hits, steps), euclid.span)
+                .with_hint("The hits go first, e.g. `C2:euclid(3, 8)`"));
+        }
+        self.expand(steps as usize, euclid.span)?;
+
+        Ok(Euclid {
+            note : Box::new(Note::single(self.notes(vec![*euclid.note], env, depth)?)),
+            hits : Expr::Value(hits),
+            steps : Expr::Value(steps),
+            rotation : Expr::Value(eval(&euclid.rotation, env)?),
+            span : euclid.span,
+        })
+    }
+
+    /// Finds the function called and binds its parameters to the arguments.
+    fn enter(&mut self, call : &Call, env : &Env, depth : usize) -> Result<(&'a Function, Env), Diagnostic> {
+        if depth >= MAX_DEPTH {
+            return Err(Diagnostic::error(format!("Too many nested calls, `{}` is called {} levels deep", call.name, depth), call.span)
+                .with_hint("A function calling itself never stops"));
+        }
+        let function = self.functions.get(&call.name)
+            .ok_or_else(|| Diagnostic::error(format!("Undefined function : {}", call.name), call.span))?;
+        let mut bound = Env::new();
+        for (param, arg) in function.params.iter().zip(&call.args) {
+            bound.insert(param.clone(), eval(arg, env)?);
+        }
+        self.expand(1, call.span)?;
+
+        Ok((function, bound))
+    }
+
+    fn expand(&mut self, notes : usize, span : Span) -> Result<(), Diagnostic> {
+        self.expansion += notes;
+        if self.expansion > MAX_EXPANSION {
+            return Err(Diagnostic::error("The code expands to too many notes".to_string(), span)
+                .with_hint(&format!("Calls can produce at most {} notes", MAX_EXPANSION)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Notes written in `notes`, those of the groups included.
+fn note_count(notes : &[Note]) -> usize {
+    notes.iter().map(|note| 1 + match &note.kind {
+        NoteKind::Group(children) | NoteKind::Alternation(children) => note_count(children),
+        NoteKind::Maybe(note, _) => note_count(std::slice::from_ref(note)),
+        NoteKind::Euclid(euclid) => note_count(std::slice::from_ref(&euclid.note)),
+        _ => 0,
+    }).sum()
+}
+
+fn returns(call : &Call, found : &str, expected : &str) -> Diagnostic {
+    Diagnostic::error(format!("`{}` returns {}, not {}", call.name, found, expected), call.span)
+}
+
+fn eval(expr : &Expr, env : &Env) -> Result<i32, Diagnostic> {
+    match expr {
+        Expr::Value(value) => Ok(*value),
+        Expr::Name(reference) => env.get(&reference.name).copied()
+            .ok_or_else(|| Diagnostic::error(format!("Undefined name : {}", reference.name), reference.span)),
+        Expr::Binary(left, operator, right, span) => {
+            let (left, right) = (eval(left, env)?, eval(right, env)?);
+            let result = match operator {
+                Operator::Add => left.checked_add(right),
+                Operator::Sub => left.checked_sub(right),
+                Operator::Mul => left.checked_mul(right),
+                Operator::Div if right == 0 => return Err(Diagnostic::error("Division by zero".to_string(), *span)),
+                Operator::Div => left.checked_div(right),
+            };
+            result.ok_or_else(|| Diagnostic::error("Arithmetic overflow".to_string(), *span))
+        },
+    }
+}
+
+
+
+/* *************TESTS*************** */
+
+#[cfg(test)]
+use super::analyse;
+
+#[cfg(test)]
+fn pitches(notes : &[Note]) -> Vec<i32> {
+    notes.iter().map(|note| match &note.kind {
+        NoteKind::Pitch(pitch) => pitch.0,
+        kind => panic!("Expected a pitch, found {:?}", kind),
+    }).collect()
+}
+
+#[test]
+fn expand_functions() {
+    let code = std::fs::read_to_string("./tests/codebase/functions.xfzd").expect("Impossible de lire le fichier");
+    let (axiom, diagnostics) = analyse(code);
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+
+    let instruments : Vec<&Instrument> = axiom.blocks.iter().map(|block| match block {
+        Block::Instrument(instrument) => instrument,
+        Block::Recursive(block) => match &block.blocks[..] {
+            [Block::Instrument(instrument)] if block.filters[0].name == "echo" => instrument,
+            blocks => panic!("Expected an instrument, found {:?}", blocks),
+        },
+        block => panic!("Expected an instrument, found {:?}", block),
+    }).collect();
+    assert_eq!(pitches(&instruments[0].notes), vec![60, 64, 67]);
+    assert_eq!(pitches(&instruments[1].notes), vec![57, 61, 64]);
+    assert_eq!(pitches(&instruments[2].notes), vec![48, 52, 55, 60, 64, 67]);
+    match &instruments[3].notes[1].kind {
+        NoteKind::Group(group) => assert_eq!(pitches(group), vec![64, 68, 71]),
+        kind => panic!("Expected a group, found {:?}", kind),
+    }
+    assert_eq!(pitches(&instruments[3].notes[2..]), vec![30]);
+    assert_eq!(pitches(&instruments[4].notes), vec![60, 64, 67]);
+}
+
+#[test]
+fn evaluation_errors() {
+    let messages = |code : &str| {
+        let (_, diagnostics) = analyse(format!("bpm 90\n4/4\n{}\n", code));
+        diagnostics.iter().map(|d| d.message.clone()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(messages("def f(x) = (f(x+1))\nsimple(f(60))"), vec!["Too many nested calls, `f` is called 32 levels deep"]);
+    assert_eq!(messages("def f(x) = (x/0)\nsimple(f(60))\nsimple(60)"), vec!["Division by zero"]);
+    assert_eq!(messages("def f(x) = (x*x)\nsimple(f(100000))"), vec!["Arithmetic overflow"]);
+    assert_eq!(messages("def arp(root) = simple(root)\nsimple(arp(60))"), vec!["`arp` returns a block, not a note list"]);
+    assert_eq!(messages("def up(root) = (root)\nup(60)"), vec!["`up` returns a note list, not a block"]);
+    assert_eq!(messages("def up(root) = (root)\nsimple(up(60, 62))"), vec!["`up` takes 1 argument, found 2"]);
+    assert_eq!(messages("def up(root) = (root)\nsimple(root+1)"), vec!["Undefined name : root"]);
+    assert_eq!(messages("def up(up) = (up)"), vec!["`up` is already defined"]);
+
+    let mut code = "def f0(x) = (x, x, x, x, x, x, x, x, x, x)".to_string();
+    for i in 1..6 {
+        code += &format!("\ndef f{}(x) = (f{}(x), f{}(x), f{}(x), f{}(x), f{}(x), f{}(x), f{}(x), f{}(x), f{}(x), f{}(x))", i, i-1, i-1, i-1, i-1, i-1, i-1, i-1, i-1, i-1, i-1);
+    }
+    assert_eq!(messages(&(code + "\nsimple(f5(60))")), vec!["The code expands to too many notes"]);
+
+    // Blocks calling blocks with long note lists
+    let mut code = format!("def b0(x) = simple({})", vec!["x"; 1000].join(", "));
+    for i in 1..4 {
+        code += &format!("\ndef b{}(x) = (\n{})", i, format!("\tb{}(x)\n", i-1).repeat(10));
+    }
+    assert_eq!(messages(&(code + "\nb3(60)")), vec!["The code expands to too many notes"]);
+}
+
+#[test]
+fn expand_random_notes() {
+    let (axiom, diagnostics) = analyse("bpm 90\n4/4\ndef around(root) = (rand(root-2, root+2), (root+7)?)\nsimple(around(60), around(C5))\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+    let maybe = |key| NoteKind::Maybe(Box::new(Note::new(NoteKind::Pitch(Pitch(key)))), 0.5);
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.notes.iter().map(|note| note.kind.clone()).collect::<Vec<_>>(), vec![
+            NoteKind::Random(Expr::Value(58), Expr::Value(62)), maybe(67),
+            NoteKind::Random(Expr::Value(70), Expr::Value(74)), maybe(79),
+        ]),
+        block => panic!("Expected an instrument, found {:?}", block),
+    }
+}
+
+#[test]
+fn evaluate_euclidean_rhythms() {
+    let (axiom, diagnostics) = analyse("bpm 90\n4/4\ndef kick(hits, root) = (root:euclid(hits, hits*2, 1)@4)\nsimple(kick(3, C2))\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => match &instrument.notes[0].kind {
+            NoteKind::Euclid(euclid) => {
+                assert_eq!(*euclid.note, Note::new(NoteKind::Pitch(Pitch(36))));
+                assert_eq!((&euclid.hits, &euclid.steps, &euclid.rotation), (&Expr::Value(3), &Expr::Value(6), &Expr::Value(1)));
+            },
+            kind => panic!("Expected a euclidean rhythm, found {:?}", kind),
+        },
+        block => panic!("Expected an instrument, found {:?}", block),
+    }
+
+    let messages = |code : &str| {
+        let (_, diagnostics) = analyse(format!("bpm 90\n4/4\n{}\n", code));
+        diagnostics.iter().map(|d| d.message.clone()).collect::<Vec<_>>()
+    };
+    assert_eq!(messages("simple(C2:euclid(9, 8)@4)"), vec!["Cannot place 9 hits on 8 steps"]);
+    assert_eq!(messages("simple(C2:euclid(-1, 8)@4)"), vec!["Cannot place -1 hits on 8 steps"]);
+    assert_eq!(messages("simple(C2:euclid(0, 0)@4)"), vec!["A euclidean rhythm has at least one step"]);
+    assert_eq!(messages("simple(C2:euclid(1, 1000000)@4)"), vec!["The code expands to too many notes"]);
+    assert_eq!(messages("simple(C2:euclid(x, 8)@4)"), vec!["Undefined name : x"]);
+}
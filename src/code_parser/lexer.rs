@@ -1,12 +1,42 @@
 use std::{iter::Peekable, str::Chars};
 
+use super::diagnostic::{Diagnostic, Span};
+
 
 #[derive(PartialEq)]
-pub(crate) enum Token {
+pub(crate) enum TokenKind {
     String(String),
+    /// Text between double quotes, such as `"hall"`
+    Text(String),
+    /// Integer without unit, such as `60` or `-12`
     Value(isize),
+    /// Number with a fractional part, an exponent or a unit, such as `128.5`,
+    /// `1e3`, `-3db` or `250ms`
+    Number(f64, Option<Unit>),
+    /// Note name such as `C4`, `D#3` or `Bb5`, as a MIDI key number
+    NoteName(i32),
+    /// Note name followed by a chord quality, such as `C4maj`
+    ChordName(i32, String),
+    /// Note name with accidentals and without octave, such as `F#` or `Bb`
+    PitchClass(i32),
     BpmKw,
+    ReferenceKw,
+    TuningKw,
+    KeyKw,
+    LetKw,
+    DefKw,
+    SectionKw,
+    SongKw,
     Solidus,
+    Plus,
+    Star,
+    Equals,
+    DoubleEquals,
+    NotEquals,
+    PercentSign,
+    Pipe,
+    Question,
+    Arrow,
     LeftABracket,
     RightABracket,
     NewLine,
@@ -14,15 +44,47 @@ pub(crate) enum Token {
     LeftParenthesis,
     RightParenthesis,
     Colon,
+    Underscore,
+    Dot,
+    Dash,
+    LeftSBracket,
+    RightSBracket,
+    LeftBrace,
+    RightBrace,
+    At,
+    Caret,
+    Eof,
 }
 
-impl core::fmt::Debug for Token {
+impl core::fmt::Debug for TokenKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::String(arg0) => f.debug_tuple("Str").field(arg0).finish(),
+            Self::Text(arg0) => f.debug_tuple("Text").field(arg0).finish(),
             Self::Value(arg0) => f.debug_tuple("Num").field(arg0).finish(),
+            Self::Number(arg0, None) => f.debug_tuple("Num").field(arg0).finish(),
+            Self::Number(arg0, Some(unit)) => f.debug_tuple("Num").field(arg0).field(unit).finish(),
+            Self::NoteName(arg0) => f.debug_tuple("Note").field(arg0).finish(),
+            Self::ChordName(arg0, arg1) => f.debug_tuple("Chord").field(arg0).field(arg1).finish(),
+            Self::PitchClass(arg0) => f.debug_tuple("PitchClass").field(arg0).finish(),
             Self::BpmKw => write!(f, "BPM"),
+            Self::ReferenceKw => write!(f, "`reference`"),
+            Self::TuningKw => write!(f, "`tuning`"),
+            Self::KeyKw => write!(f, "`key`"),
+            Self::LetKw => write!(f, "`let`"),
+            Self::DefKw => write!(f, "`def`"),
+            Self::SectionKw => write!(f, "`section`"),
+            Self::SongKw => write!(f, "`song`"),
             Self::Solidus => write!(f, "`/`"),
+            Self::Plus => write!(f, "`+`"),
+            Self::Star => write!(f, "`*`"),
+            Self::Equals => write!(f, "`=`"),
+            Self::DoubleEquals => write!(f, "`==`"),
+            Self::NotEquals => write!(f, "`!=`"),
+            Self::PercentSign => write!(f, "`%`"),
+            Self::Pipe => write!(f, "`|`"),
+            Self::Question => write!(f, "`?`"),
+            Self::Arrow => write!(f, "`->`"),
             Self::LeftABracket => write!(f, "`<`"),
             Self::RightABracket => write!(f, "`>`"),
             Self::NewLine => write!(f, "NewLine"),
@@ -30,82 +92,503 @@ impl core::fmt::Debug for Token {
             Self::LeftParenthesis => write!(f, "`(`"),
             Self::RightParenthesis => write!(f, "`)`"),
             Self::Colon => write!(f, "`:`"),
+            Self::Underscore => write!(f, "`_`"),
+            Self::Dot => write!(f, "`.`"),
+            Self::Dash => write!(f, "`-`"),
+            Self::LeftSBracket => write!(f, "`[`"),
+            Self::RightSBracket => write!(f, "`]`"),
+            Self::LeftBrace => write!(f, "`{{`"),
+            Self::RightBrace => write!(f, "`}}`"),
+            Self::At => write!(f, "`@`"),
+            Self::Caret => write!(f, "`^`"),
+            Self::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+/// Unit written right after a number
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Unit {
+    /// `ms`
+    Milliseconds,
+    /// `hz`
+    Hertz,
+    /// `db`
+    Decibels,
+    /// `%`
+    Percent,
+}
+
+impl std::fmt::Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Milliseconds => write!(f, "ms"),
+            Self::Hertz => write!(f, "hz"),
+            Self::Decibels => write!(f, "db"),
+            Self::Percent => write!(f, "%"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum TriviaKind {
+    /// `// ...` or `# ...`, up to the end of the line
+    LineComment,
+    /// `/* ... */`
+    BlockComment,
+}
+
+/// Piece of code with no meaning for the parser, kept for the formatter.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub(crate) struct Trivia {
+    pub kind : TriviaKind,
+    /// Text of the comment, delimiters included
+    pub text : String,
+    pub span : Span,
+}
+
+#[derive(Debug)]
+pub(crate) struct Token {
+    pub kind : TokenKind,
+    pub span : Span,
+    /// Comments found between the previous token and this one
+    pub leading_trivia : Vec<Trivia>,
+}
+
+/// Character iterator keeping track of the position in the code.
+#[derive(Clone)]
+struct Cursor<'a> {
+    chars : Peekable<Chars<'a>>,
+    offset : usize,
+    line : usize,
+    column : usize,
+}
+
+impl Cursor<'_> {
+    fn new(code : &str) -> Cursor<'_> {
+        Cursor { chars : code.chars().peekable(), offset : 0, line : 1, column : 1 }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// Character after the next one
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.offset += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        }
+        else {
+            self.column += 1;
         }
+        Some(c)
+    }
+
+    /// Empty span at the current position
+    fn position(&self) -> Span {
+        Span { start : self.offset, end : self.offset, line : self.line, column : self.column }
+    }
+
+    /// Span from `start` to the current position
+    fn span_from(&self, start : Span) -> Span {
+        Span { end : self.offset, ..start }
     }
 }
 
-pub(crate) fn tokenizer(code : String) -> Result<Vec<Token>,String> {
-    let mut code_iter = code.chars().peekable();
-    let mut tokens = Vec::new();
+/// Splits the code into tokens, always ending with `Eof`.
+/// Lexing errors are reported as diagnostics, the faulty characters are skipped.
+pub(crate) fn tokenizer(code : String) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut code_iter = Cursor::new(&code);
+    let mut tokens : Vec<Token> = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut trivia = Vec::new();
+    let push = |tokens : &mut Vec<Token>, kind, span, trivia : &mut Vec<Trivia>| {
+        tokens.push(Token {kind, span, leading_trivia : std::mem::take(trivia)});
+    };
 
     loop {
-        match code_iter.peek() {
-            Some('<') => tokens.push(Token::LeftABracket),
-            Some('>') => tokens.push(Token::RightABracket),
-            Some('(') => tokens.push(Token::LeftParenthesis),
-            Some(')') => tokens.push(Token::RightParenthesis),
-            Some(',') => tokens.push(Token::Comma),
-            Some(':') => tokens.push(Token::Colon),
-            Some('/') => tokens.push(Token::Solidus),
+        let start = code_iter.position();
+        let kind = match code_iter.peek().copied() {
+            Some('#') => {
+                trivia.push(parse_line_comment(&mut code_iter));
+                continue;
+            },
+            Some('/') if code_iter.peek_second() == Some('/') => {
+                trivia.push(parse_line_comment(&mut code_iter));
+                continue;
+            },
+            Some('/') if code_iter.peek_second() == Some('*') => {
+                let (comment, diagnostic) = parse_block_comment(&mut code_iter);
+                trivia.push(comment);
+                diagnostics.extend(diagnostic);
+                continue;
+            },
+            Some('<') => TokenKind::LeftABracket,
+            Some('>') => TokenKind::RightABracket,
+            Some('(') => TokenKind::LeftParenthesis,
+            Some(')') => TokenKind::RightParenthesis,
+            Some(',') => TokenKind::Comma,
+            Some(':') => TokenKind::Colon,
+            Some('_') => TokenKind::Underscore,
+            Some('.') => TokenKind::Dot,
+            Some('-') if code_iter.peek_second() == Some('>') => {
+                code_iter.next();
+                TokenKind::Arrow
+            },
+            Some('-') if code_iter.peek_second().is_some_and(|c| c.is_ascii_digit())
+                && tokens.last().is_none_or(|token| may_precede_negative_number(&token.kind)) => {
+                match parse_number(&mut code_iter) {
+                    Ok(kind) => push(&mut tokens, kind, code_iter.span_from(start), &mut trivia),
+                    Err(diagnostic) => diagnostics.push(diagnostic),
+                }
+                continue;
+            },
+            Some('-') => TokenKind::Dash,
+            Some('[') => TokenKind::LeftSBracket,
+            Some(']') => TokenKind::RightSBracket,
+            Some('{') => TokenKind::LeftBrace,
+            Some('}') => TokenKind::RightBrace,
+            Some('@') => TokenKind::At,
+            Some('^') => TokenKind::Caret,
+            Some('/') => TokenKind::Solidus,
+            Some('+') => TokenKind::Plus,
+            Some('*') => TokenKind::Star,
+            Some('=') if code_iter.peek_second() == Some('=') => {
+                code_iter.next();
+                TokenKind::DoubleEquals
+            },
+            Some('!') if code_iter.peek_second() == Some('=') => {
+                code_iter.next();
+                TokenKind::NotEquals
+            },
+            Some('=') => TokenKind::Equals,
+            // After a number, `%` is read as a unit
+            Some('%') => TokenKind::PercentSign,
+            Some('|') => TokenKind::Pipe,
+            Some('?') => TokenKind::Question,
+            Some('"') => {
+                match parse_text(&mut code_iter) {
+                    Ok(kind) => push(&mut tokens, kind, code_iter.span_from(start), &mut trivia),
+                    Err(diagnostic) => diagnostics.push(diagnostic),
+                }
+                continue;
+            },
             Some('\n') => {
-                if tokens.last() != Some(&Token::NewLine) {
-                    tokens.push(Token::NewLine)
+                code_iter.next();
+                // Blank lines at the beginning are ignored, as repeated new lines
+                match tokens.last_mut() {
+                    None => {},
+                    Some(token) if token.kind == TokenKind::NewLine => token.leading_trivia.append(&mut trivia),
+                    _ => push(&mut tokens, TokenKind::NewLine, code_iter.span_from(start), &mut trivia),
                 }
+                continue;
             },
             Some('0'..='9') => {
-                tokens.push(parse_number(&mut code_iter)?);
+                match parse_number(&mut code_iter) {
+                    Ok(kind) => push(&mut tokens, kind, code_iter.span_from(start), &mut trivia),
+                    Err(diagnostic) => diagnostics.push(diagnostic),
+                }
                 continue;
             },
             Some('a'..='z' | 'A'..='Z') => {
-                tokens.push(parse_string(&mut code_iter)?);
+                let kind = match parse_note_name(&code_iter) {
+                    Some((kind, after)) => {
+                        code_iter = after;
+                        kind
+                    },
+                    None => parse_string(&mut code_iter),
+                };
+                push(&mut tokens, kind, code_iter.span_from(start), &mut trivia);
+                continue;
+            },
+            Some('\r' | ' ' | '\t') => {
+                code_iter.next();
                 continue;
             },
-            Some('\r') => {},
-            Some(' ') => {},
-            Some('\t') => {},
             None => break,
-            Some(c) => panic!("Unexpected character : {}",c),
-        }
+            Some(_) => {
+                diagnostics.push(skip_unexpected(&mut code_iter));
+                continue;
+            },
+        };
         code_iter.next();
+        push(&mut tokens, kind, code_iter.span_from(start), &mut trivia);
     }
 
-    Ok(tokens)
+    push(&mut tokens, TokenKind::Eof, code_iter.position(), &mut trivia);
+    (tokens, diagnostics)
 }
 
-fn parse_number(code_iter : &mut Peekable<Chars>) -> Result<Token, String> {
-    let mut number : isize = 0;
-    let mut c : Option<&char>;
+/// Reads a text between double quotes, on a single line.
+fn parse_text(code_iter : &mut Cursor) -> Result<TokenKind, Diagnostic> {
+    let start = code_iter.position();
+    code_iter.next();
+    let mut text = String::new();
     loop {
-        c = code_iter.peek();
-        match c {
-            Some('0'..='9') => {
-                let digit = c.unwrap().to_digit(10).ok_or("Failed to parse digit")?;
-                number = 10*number + digit as isize;
-                
+        match code_iter.peek() {
+            Some('"') => {
+                code_iter.next();
+                return Ok(TokenKind::Text(text));
+            },
+            Some('\n') | None => return Err(Diagnostic::error("Unterminated text".to_string(), code_iter.span_from(start))
+                .with_hint("Texts end with `\"` on the same line")),
+            Some(&c) => {
+                text.push(c);
+                code_iter.next();
+            },
+        }
+    }
+}
+
+/// Whether a `-` directly followed by digits after `previous` is a sign
+/// rather than a hold: it is not after something a hold could follow.
+fn may_precede_negative_number(previous : &TokenKind) -> bool {
+    !matches!(previous,
+        TokenKind::Value(_) | TokenKind::Number(..) | TokenKind::String(_)
+        | TokenKind::NoteName(_) | TokenKind::ChordName(..) | TokenKind::PitchClass(_)
+        | TokenKind::RightParenthesis | TokenKind::RightSBracket | TokenKind::RightBrace
+        | TokenKind::Underscore | TokenKind::Dot | TokenKind::Dash
+    )
+}
+
+/// Reads a number: an optional `-`, digits, an optional fractional part and
+/// exponent, then an optional unit. Integers without unit are `Value`s.
+fn parse_number(code_iter : &mut Cursor) -> Result<TokenKind, Diagnostic> {
+    let start = code_iter.position();
+    let mut text = String::new();
+    let mut is_integer = true;
+    let read_digits = |code_iter : &mut Cursor, text : &mut String| {
+        while let Some(&digit) = code_iter.peek().filter(|c| c.is_ascii_digit()) {
+            text.push(digit);
+            code_iter.next();
+        }
+    };
+
+    if code_iter.peek() == Some(&'-') {
+        text.push('-');
+        code_iter.next();
+    }
+    read_digits(code_iter, &mut text);
+
+    // Fractional part, a dot without digits after it being a rest
+    if code_iter.peek() == Some(&'.') && code_iter.peek_second().is_some_and(|c| c.is_ascii_digit()) {
+        is_integer = false;
+        text.push('.');
+        code_iter.next();
+        read_digits(code_iter, &mut text);
+    }
+
+    // Exponent, only if digits follow so that `31edo` is still a number and a name
+    let mut lookahead = code_iter.clone();
+    if matches!(lookahead.next(), Some('e' | 'E')) {
+        if matches!(lookahead.peek(), Some('+' | '-')) {
+            lookahead.next();
+        }
+        if lookahead.peek().is_some_and(|c| c.is_ascii_digit()) {
+            is_integer = false;
+            text.push('e');
+            code_iter.next();
+            if let Some(&sign) = code_iter.peek().filter(|&&c| c == '+' || c == '-') {
+                text.push(sign);
+                code_iter.next();
             }
-            _ => return Ok(Token::Value(number))
+            read_digits(code_iter, &mut text);
+        }
+    }
+
+    let unit = parse_unit(code_iter);
+    let too_large = || Diagnostic::error("Number too large".to_string(), code_iter.span_from(start));
+    if is_integer && unit.is_none() {
+        return text.parse().map(TokenKind::Value).map_err(|_| too_large());
+    }
+
+    let value : f64 = text.parse().unwrap();
+    if !value.is_finite() {
+        return Err(too_large());
+    }
+    Ok(TokenKind::Number(value, unit))
+}
+
+/// Reads a unit if one directly follows, a longer word not being a unit.
+fn parse_unit(code_iter : &mut Cursor) -> Option<Unit> {
+    if code_iter.peek() == Some(&'%') {
+        code_iter.next();
+        return Some(Unit::Percent);
+    }
+
+    let mut lookahead = code_iter.clone();
+    let mut word = String::new();
+    while let Some(&c) = lookahead.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+        word.push(c);
+        lookahead.next();
+    }
+    let unit = match &word.to_lowercase()[..] {
+        "ms" => Unit::Milliseconds,
+        "hz" => Unit::Hertz,
+        "db" => Unit::Decibels,
+        _ => return None,
+    };
+    *code_iter = lookahead;
+
+    Some(unit)
+}
+
+/// Skips a run of characters the lexer does not know, up to the next
+/// whitespace or known character.
+fn skip_unexpected(code_iter : &mut Cursor) -> Diagnostic {
+    let start = code_iter.position();
+    let mut collector = String::new();
+    while let Some(&c) = code_iter.peek() {
+        if c.is_ascii_alphanumeric() || c.is_whitespace() || "<>(),:/#_.-+*=[]{}@^%|?\"".contains(c) {
+            break;
+        }
+        collector.push(c);
+        code_iter.next();
+    }
+
+    Diagnostic::error(format!("Unexpected character : `{}`", collector), code_iter.span_from(start))
+}
+
+/// Reads a comment up to the end of the line, the new line excluded.
+fn parse_line_comment(code_iter : &mut Cursor) -> Trivia {
+    let start = code_iter.position();
+    let mut text = String::new();
+    while let Some(&c) = code_iter.peek() {
+        if c == '\n' {
+            break;
+        }
+        text.push(c);
+        code_iter.next();
+    }
+
+    Trivia {kind : TriviaKind::LineComment, text, span : code_iter.span_from(start)}
+}
+
+/// Reads a `/* */` comment. Block comments do not nest.
+fn parse_block_comment(code_iter : &mut Cursor) -> (Trivia, Option<Diagnostic>) {
+    let start = code_iter.position();
+    let mut text = String::new();
+    // Opening `/*`
+    text.extend(code_iter.next());
+    text.extend(code_iter.next());
+
+    let mut diagnostic = None;
+    loop {
+        match code_iter.next() {
+            Some('*') if code_iter.peek() == Some(&'/') => {
+                text.push('*');
+                text.extend(code_iter.next());
+                break;
+            },
+            Some(c) => text.push(c),
+            None => {
+                diagnostic = Some(Diagnostic::error("Unterminated comment".to_string(), code_iter.span_from(start))
+                    .with_hint("Block comments end with `*/`"));
+                break;
+            },
+        }
+    }
+
+    (Trivia {kind : TriviaKind::BlockComment, text, span : code_iter.span_from(start)}, diagnostic)
+}
+
+/// Reads a note name: an upper-case letter from `A` to `G`, sharps (`#`) or
+/// flats (`b`), then the octave, the middle C being `C4`, and optionally a
+/// chord quality. Without octave, a name with accidentals is a pitch class.
+/// Returns `None`, without consuming anything, if this is not a note name.
+fn parse_note_name<'a>(code_iter : &Cursor<'a>) -> Option<(TokenKind, Cursor<'a>)> {
+    let mut code_iter = code_iter.clone();
+    let pitch_class : i32 = match code_iter.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+
+    let mut accidental = 0;
+    loop {
+        match code_iter.peek() {
+            Some('#') => accidental += 1,
+            Some('b') => accidental -= 1,
+            _ => break,
+        }
+        code_iter.next();
+    }
+
+    let has_octave = match code_iter.peek() {
+        Some('-') => code_iter.peek_second().is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    };
+    if !has_octave {
+        let is_word_end = !code_iter.peek().is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_');
+        if accidental != 0 && is_word_end {
+            return Some((TokenKind::PitchClass((pitch_class + accidental).rem_euclid(12)), code_iter));
+        }
+        return None;
+    }
+
+    let negative_octave = code_iter.peek() == Some(&'-');
+    if negative_octave {
+        code_iter.next();
+    }
+    let octave = code_iter.next()?.to_digit(10)? as i32;
+    let octave = if negative_octave {-octave} else {octave};
+
+    let key = 12 * (octave + 1) + pitch_class + accidental;
+
+    // Chord quality
+    let mut quality = String::new();
+    while let Some(&c) = code_iter.peek() {
+        if !c.is_ascii_alphanumeric() {
+            break;
         }
+        quality.push(c);
         code_iter.next();
     }
+    if quality.is_empty() {
+        Some((TokenKind::NoteName(key), code_iter))
+    }
+    else {
+        Some((TokenKind::ChordName(key, quality), code_iter))
+    }
 }
 
-fn parse_string(code_iter : &mut Peekable<Chars>) -> Result<Token, String> {
+fn parse_string(code_iter : &mut Cursor) -> TokenKind {
     let mut collector : String = String::new();
     let mut c : Option<&char>;
     'a : loop {
         c = code_iter.peek();
         match c {
-            Some('a'..='z' | 'A'..='Z' | '_') => collector.push(*c.unwrap()),
+            Some('a'..='z' | 'A'..='Z' | '0'..='9' | '_') => collector.push(*c.unwrap()),
             _ => break 'a,
         }
         code_iter.next();
     }
 
-    if collector.to_uppercase() == "BPM" {
-        return Ok(Token::BpmKw);
+    match &collector.to_lowercase()[..] {
+        "bpm" => TokenKind::BpmKw,
+        "reference" => TokenKind::ReferenceKw,
+        "tuning" => TokenKind::TuningKw,
+        "key" => TokenKind::KeyKw,
+        "let" => TokenKind::LetKw,
+        "def" => TokenKind::DefKw,
+        "section" => TokenKind::SectionKw,
+        "song" => TokenKind::SongKw,
+        _ => TokenKind::String(collector),
     }
-
-    Ok(Token::String(collector))
 }
 
 
@@ -120,6 +603,150 @@ fn tokenize_plain() {
     let plain_code = std::fs::read_to_string("./tests/codebase/plain.xfzd")
         .expect("Impossible de lire le fichier");
 
-    let tokens = tokenizer(plain_code).unwrap();
+    let (tokens, diagnostics) = tokenizer(plain_code);
+    assert!(diagnostics.is_empty());
     dbg!(tokens);
-}
\ No newline at end of file
+}
+
+#[test]
+fn tokens_have_spans() {
+    let (tokens, _) = tokenizer("bpm 90\n3/4\n".to_string());
+
+    assert_eq!(tokens[1].kind, TokenKind::Value(90));
+    assert_eq!(tokens[1].span, Span {start : 4, end : 6, line : 1, column : 5});
+    assert_eq!(tokens[4].kind, TokenKind::Solidus);
+    assert_eq!(tokens[4].span, Span {start : 8, end : 9, line : 2, column : 2});
+}
+
+#[test]
+fn unexpected_characters_are_skipped() {
+    let (tokens, diagnostics) = tokenizer("simple(1, $;! é, 99999999999999999999999, 2)".to_string());
+
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds, vec![
+        &TokenKind::String("simple".to_string()), &TokenKind::LeftParenthesis,
+        &TokenKind::Value(1), &TokenKind::Comma, &TokenKind::Comma, &TokenKind::Comma,
+        &TokenKind::Value(2), &TokenKind::RightParenthesis, &TokenKind::Eof,
+    ]);
+    let messages : Vec<&str> = diagnostics.iter().map(|d| &d.message[..]).collect();
+    assert_eq!(messages, vec!["Unexpected character : `$;!`", "Unexpected character : `é`", "Number too large"]);
+    assert_eq!(diagnostics[1].span.column, 15);
+}
+
+#[test]
+fn comments_are_kept_as_trivia() {
+    let code = std::fs::read_to_string("./tests/codebase/comments.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let (tokens, diagnostics) = tokenizer(code.clone());
+    assert!(diagnostics.is_empty());
+
+    let comments : Vec<&Trivia> = tokens.iter().flat_map(|token| &token.leading_trivia).collect();
+    assert_eq!(comments.len(), 6);
+    for comment in comments {
+        let text : String = code.chars().skip(comment.span.start).take(comment.span.end - comment.span.start).collect();
+        assert_eq!(comment.text, text);
+    }
+    assert!(tokens.iter().all(|token| !matches!(&token.kind, TokenKind::String(s) if s == "mute")));
+}
+
+#[test]
+fn unterminated_block_comment() {
+    let (tokens, diagnostics) = tokenizer("bpm 90 /* 3/4".to_string());
+
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[2].leading_trivia[0].kind, TriviaKind::BlockComment);
+    assert_eq!(diagnostics[0].message, "Unterminated comment");
+}
+
+#[test]
+fn note_names() {
+    let (tokens, diagnostics) = tokenizer("C4 D#3 Bb5 C-1 Ab Cbb4 E#4 A3m7 F# A Abc C-".to_string());
+
+    assert!(diagnostics.is_empty());
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds, vec![
+        &TokenKind::NoteName(60), &TokenKind::NoteName(51), &TokenKind::NoteName(82),
+        &TokenKind::NoteName(0), &TokenKind::PitchClass(8),
+        &TokenKind::NoteName(58), &TokenKind::NoteName(65),
+        &TokenKind::ChordName(57, "m7".to_string()), &TokenKind::PitchClass(6),
+        &TokenKind::String("A".to_string()), &TokenKind::String("Abc".to_string()),
+        &TokenKind::String("C".to_string()), &TokenKind::Dash, &TokenKind::Eof,
+    ]);
+}
+
+#[test]
+fn decimal_numbers_and_arrows() {
+    let (tokens, diagnostics) = tokenizer("bpm 128.5 -> 140\n1. 2 - 3".to_string());
+
+    assert!(diagnostics.is_empty());
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds, vec![
+        &TokenKind::BpmKw, &TokenKind::Number(128.5, None), &TokenKind::Arrow, &TokenKind::Value(140), &TokenKind::NewLine,
+        &TokenKind::Value(1), &TokenKind::Dot, &TokenKind::Value(2), &TokenKind::Dash, &TokenKind::Value(3), &TokenKind::Eof,
+    ]);
+}
+
+#[test]
+fn numeric_literals() {
+    let (tokens, diagnostics) = tokenizer("q:0.7 pan:-30 1e3 2.5E-2 gain:-3db 250ms 440Hz 70% 31edo 4msx".to_string());
+
+    assert!(diagnostics.is_empty());
+    let numbers : Vec<&TokenKind> = tokens.iter()
+        .map(|token| &token.kind)
+        .filter(|kind| matches!(kind, TokenKind::Value(_) | TokenKind::Number(..)))
+        .collect();
+    assert_eq!(numbers, vec![
+        &TokenKind::Number(0.7, None), &TokenKind::Value(-30), &TokenKind::Number(1000., None),
+        &TokenKind::Number(0.025, None), &TokenKind::Number(-3., Some(Unit::Decibels)),
+        &TokenKind::Number(250., Some(Unit::Milliseconds)), &TokenKind::Number(440., Some(Unit::Hertz)),
+        &TokenKind::Number(70., Some(Unit::Percent)), &TokenKind::Value(31), &TokenKind::Value(4),
+    ]);
+
+    // After a note, a dash is a hold
+    let (tokens, _) = tokenizer("[60 -1] (-2)".to_string());
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds[1..5], [&TokenKind::Value(60), &TokenKind::Dash, &TokenKind::Value(1), &TokenKind::RightSBracket]);
+    assert_eq!(kinds[6], &TokenKind::Value(-2));
+
+    let (_, diagnostics) = tokenizer("99999999999999999999 1e999".to_string());
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|diagnostic| diagnostic.message == "Number too large"));
+}
+
+#[test]
+fn quoted_texts() {
+    let (tokens, diagnostics) = tokenizer("<reverb: room=\"large hall\">\n\"oops\n".to_string());
+
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds[3..6], [&TokenKind::String("room".to_string()), &TokenKind::Equals, &TokenKind::Text("large hall".to_string())]);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Unterminated text");
+}
+
+#[test]
+fn conditions_and_alternations() {
+    let (tokens, diagnostics) = tokenizer("bar%8==7 bar % 2 != 0 = (1 | 5) 70% !".to_string());
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Unexpected character : `!`");
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds, vec![
+        &TokenKind::String("bar".to_string()), &TokenKind::PercentSign, &TokenKind::Value(8), &TokenKind::DoubleEquals, &TokenKind::Value(7),
+        &TokenKind::String("bar".to_string()), &TokenKind::PercentSign, &TokenKind::Value(2), &TokenKind::NotEquals, &TokenKind::Value(0),
+        &TokenKind::Equals, &TokenKind::LeftParenthesis, &TokenKind::Value(1), &TokenKind::Pipe, &TokenKind::Value(5), &TokenKind::RightParenthesis,
+        &TokenKind::Number(70., Some(Unit::Percent)), &TokenKind::Eof,
+    ]);
+}
+
+#[test]
+fn question_marks() {
+    let (tokens, diagnostics) = tokenizer("C4? 62?30% ?0.5".to_string());
+
+    assert!(diagnostics.is_empty());
+    let kinds : Vec<&TokenKind> = tokens.iter().map(|token| &token.kind).collect();
+    assert_eq!(kinds, vec![
+        &TokenKind::NoteName(60), &TokenKind::Question, &TokenKind::Value(62), &TokenKind::Question,
+        &TokenKind::Number(30., Some(Unit::Percent)), &TokenKind::Question, &TokenKind::Number(0.5, None), &TokenKind::Eof,
+    ]);
+}
@@ -1,2 +1,20 @@
+pub mod diagnostic;
+pub mod evaluator;
 pub mod lexer;
-pub mod parser;
\ No newline at end of file
+pub mod parser;
+pub mod resolver;
+
+use diagnostic::Diagnostic;
+use parser::Axiom;
+
+/// Parses the code, resolves the names it binds and expands the function
+/// calls, so that the axiom is ready to be played.
+pub fn analyse(code : String) -> (Option<Axiom>, Vec<Diagnostic>) {
+    let (mut axiom, mut diagnostics) = parser::parse(code);
+    if let Some(axiom) = axiom.as_mut() {
+        let functions = resolver::resolve(axiom, &mut diagnostics);
+        evaluator::evaluate(axiom, &functions, &mut diagnostics);
+    }
+
+    (axiom, diagnostics)
+}
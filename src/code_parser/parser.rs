@@ -2,138 +2,759 @@
 
 use std::{convert::TryInto, fmt::Debug};
 
-use super::lexer::{tokenizer, Token as T};
+use super::diagnostic::{Diagnostic, Span};
+pub(crate) use super::lexer::Unit;
+use super::lexer::{tokenizer, Token, TokenKind as T};
+use crate::theory::{chord_intervals, groove_template, parse_numeral, scale_intervals, ChordDegree, Groove, Key};
+
+/// Slowest and fastest tempos, in quarter notes per minute
+const MIN_BPM : f32 = 1.;
+const MAX_BPM : f32 = 1000.;
 
 #[derive(Debug)]
 pub struct Axiom {
-    pub bpm : u8,
-    pub signature : (u8, u8),
+    pub tempo : Tempo,
+    pub signature : Signature,
+    /// Frequency of `A4` in Hz, if not the standard one
+    pub reference : Option<f32>,
+    /// Tuning system, if not twelve-tone equal temperament
+    pub tuning : Option<TuningName>,
+    /// Key of the scale degrees and chord symbols
+    pub key : Option<Key>,
+    /// Sections in the order they are played, one bar each
+    pub song : Option<Vec<Reference>>,
+    /// Groove of all the blocks, unless they have one
+    pub swing : Option<Groove>,
     pub blocks : Vec<Block>
 }
 
+/// Tempo in quarter notes per minute, written `bpm 90` or, to speed up or
+/// slow down along each bar, `bpm 120 -> 140`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tempo {
+    /// Tempo at the beginning of the bar
+    pub start : f32,
+    /// Tempo reached at the end of the bar
+    pub end : f32,
+}
+
+impl Tempo {
+    /// Time in seconds from the beginning of a bar of `length` quarter notes
+    /// to `position`, the tempo changing linearly with the position.
+    pub fn seconds(&self, position : f32, length : f32) -> f32 {
+        let slope = (self.end - self.start) / length;
+        if slope == 0. {
+            return 60. * position / self.start;
+        }
+
+        60. / slope * (slope * position / self.start).ln_1p()
+    }
+}
+
+/// Time signature, such as `4/4`, `6/8` or `3+2+2/8`
+#[derive(Debug, PartialEq)]
+pub struct Signature {
+    /// Upper numbers, several for additive meters such as `3+2+2/8`
+    pub groups : Vec<u8>,
+    /// Note value of a unit, `4` for a quarter note
+    pub denominator : u8,
+}
+
+impl Signature {
+    /// Number of units in a bar
+    pub fn numerator(&self) -> u32 {
+        self.groups.iter().map(|&group| group as u32).sum()
+    }
+
+    /// Length of the bar in quarter notes
+    pub fn length(&self) -> f32 {
+        self.numerator() as f32 * 4. / self.denominator as f32
+    }
+
+    /// Lengths of the pulses of the bar in quarter notes, the top-level notes
+    /// of an instrument being one pulse long. Additive meters have a pulse per
+    /// group, compound meters such as `6/8` or `12/8` a dotted pulse per three
+    /// units, and the others a pulse per unit.
+    pub fn pulses(&self) -> Vec<f32> {
+        let unit = 4. / self.denominator as f32;
+        match self.groups[..] {
+            [count] if self.denominator >= 8 && count > 3 && count % 3 == 0 => vec![3. * unit; count as usize / 3],
+            [count] => vec![unit; count as usize],
+            _ => self.groups.iter().map(|&group| group as f32 * unit).collect(),
+        }
+    }
+}
+
+/// Name of a tuning, resolved when building the sound
 #[derive(Debug)]
+pub struct TuningName {
+    pub name : String,
+    pub span : Span,
+}
+
+#[derive(Clone, Debug)]
 pub enum Block {
     Recursive(RecBlock),
     Instrument(Instrument),
+    /// Binding of a name to what follows it in the enclosing block
+    Let(Let),
+    /// Name of a block bound with `let`
+    Reference(Reference),
+    /// Definition of a function, removed by the resolver
+    Def(Def),
+    /// Call of a function returning a block, written like an instrument and
+    /// told apart by the resolver
+    Call(Call),
+    /// Blocks played only in the bars the song gives to them
+    Section(Section),
 }
 
-#[derive(Debug)]
+/// `section name (blocks)`, written at the top level
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name : String,
+    pub span : Span,
+    pub blocks : Vec<Block>,
+}
+
+/// `def name(param, ...) = value`, the value being a note list or a block
+#[derive(Clone, Debug)]
+pub struct Def {
+    pub name : String,
+    pub params : Vec<String>,
+    pub span : Span,
+    pub body : LetValue,
+}
+
+/// `name(arg, ...)`, expanded by the evaluator
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub name : String,
+    pub args : Vec<Expr>,
+    pub span : Span,
+}
+
+/// Arithmetic on pitches, such as `root+4`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// Number or note name
+    Value(i32),
+    /// Parameter of the enclosing function
+    Name(Reference),
+    /// Operation, with the span of its operator
+    Binary(Box<Expr>, Operator, Box<Expr>, Span),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// `let name = value`, removed by the resolver
+#[derive(Clone, Debug)]
+pub struct Let {
+    pub name : String,
+    pub span : Span,
+    pub value : LetValue,
+}
+
+#[derive(Clone, Debug)]
+pub enum LetValue {
+    /// `(note, note, ...)`
+    Notes(Vec<Note>),
+    /// `<filter, filter, ...>`
+    Filters(Vec<Filter>),
+    Block(Box<Block>),
+}
+
+/// Use of a name bound with `let`, replaced by its value by the resolver
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference {
+    pub name : String,
+    pub span : Span,
+}
+
+#[derive(Clone, Debug)]
 pub struct RecBlock {
     pub filters : Vec<Filter>,
     pub blocks : Vec<Block>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Instrument {
     pub instrument : String,
     pub filters : Vec<Filter>,
-    pub notes : Vec<Note>
+    pub notes : Vec<Note>,
+    pub span : Span,
 }
 
-// TODO : string value
-#[derive(Clone, Debug)]
+/// Effect applied to the sound of a block, written `name:arg arg ...`, the
+/// arguments being separated by spaces and possibly named, as in
+/// `echo:time=250ms feedback=0.6`.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Filter {
     pub name : String,
-    pub value : isize, 
+    pub args : Vec<FilterArg>,
+    pub span : Span,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterArg {
+    /// Name of the parameter, if written `name=value`
+    pub name : Option<String>,
+    pub value : FilterValue,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterValue {
+    /// Number, with the unit written after it, e.g. `hz` in `800hz`
+    Number(f64, Option<Unit>),
+    /// Text between double quotes
+    Text(String),
+    /// Name without quotes, such as `saw`
+    Ident(String),
+    /// `[value value ...]`
+    List(Vec<FilterValue>),
+    /// Test on the index of the bar, such as `bar%8==7`
+    Condition(Condition),
+}
+
+/// `bar%period==value`, or `bar%period!=value`, the period being optional
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub period : Option<u32>,
+    /// Whether the test is `!=` rather than `==`
+    pub negated : bool,
+    pub value : u32,
+}
+
+impl Condition {
+    /// Whether the test passes in bar `bar_index`, counted from 0.
+    pub fn holds(&self, bar_index : u32) -> bool {
+        let bar = match self.period {
+            Some(period) => bar_index % period,
+            None => bar_index,
+        };
+        (bar == self.value) != self.negated
+    }
+}
+
+/// MIDI key number: 60 is the middle C (`C4`), 69 is `A4`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pitch(pub i32);
+
+/// Element of a note list, sharing the time of its list with its siblings
+/// according to their weights.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Note {
-    pub pitch : usize,
+    pub kind : NoteKind,
+    /// Relative length among the siblings, `1` unless written `note@weight`
+    pub weight : u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NoteKind {
+    /// Written as a MIDI key number or as a note name
+    Pitch(Pitch),
+    /// Pitches played together, written `{pitch pitch ...}` or as a chord
+    /// name such as `C4maj`
+    Chord(Vec<Pitch>),
+    /// Silence, written `_` or `.`
+    Rest,
+    /// Degree of the scale of the key, counted from 1, written `^n`
+    Degree(u32),
+    /// Chord built on a degree of the key, written as a roman numeral such as
+    /// `ii7` or `V`
+    ChordDegree(ChordDegree),
+    /// Holds the previous note for its own length, written `-`
+    Hold,
+    /// Subdivision of the note's length, written `[note note ...]`
+    Group(Vec<Note>),
+    /// Name of a note list bound with `let`, its notes taking its place
+    Reference(Reference),
+    /// Pitch computed by the evaluator
+    Expr(Expr),
+    /// Call of a function returning a note list, its notes taking its place
+    Call(Call),
+    /// Notes played one per bar in turn, written `(note | note | ...)`
+    Alternation(Vec<Note>),
+    /// Note played only with the given probability, otherwise a rest, written
+    /// `note?` for an even chance or `note?30%`
+    Maybe(Box<Note>, f32),
+    /// Pitch drawn between two pitches, both included, written `rand(low, high)`
+    Random(Expr, Expr),
+    /// Note repeated on the hits of a euclidean rhythm, written
+    /// `note:euclid(hits, steps, rotation)`
+    Euclid(Euclid),
 }
 
+/// Rhythm spreading `hits` as evenly as possible over `steps` equal steps of
+/// the note's length, the others being rests. The rhythm starts `rotation`
+/// steps later, `0` when it is left out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Euclid {
+    pub note : Box<Note>,
+    pub hits : Expr,
+    pub steps : Expr,
+    pub rotation : Expr,
+    pub span : Span,
+}
 
-pub fn parse(code : String) -> Result<Axiom,String> {
-    let token_list = tokenizer(code)?;
-    let axiom = syntactical_analysis(&token_list)?;
+impl Note {
+    pub fn new(kind : NoteKind) -> Note {
+        Note { kind, weight : 1 }
+    }
 
-    Ok(axiom)
+    /// The only note of `notes`, or a group of them.
+    pub fn single(mut notes : Vec<Note>) -> Note {
+        match notes.len() {
+            1 => notes.pop().unwrap(),
+            _ => Note::new(NoteKind::Group(notes)),
+        }
+    }
 }
 
-fn syntactical_analysis(tokens : &Vec<T>) -> Result<Axiom,String> {
+
+/// Parses the code as far as possible. Blocks with errors are left out of the
+/// axiom and reported as diagnostics, there is no axiom at all only when the
+/// header (tempo and time signature) cannot be read.
+pub fn parse(code : String) -> (Option<Axiom>, Vec<Diagnostic>) {
+    let (token_list, mut diagnostics) = tokenizer(code);
+    let axiom = syntactical_analysis(&token_list, &mut diagnostics);
+
+    (axiom, diagnostics)
+}
+
+fn syntactical_analysis(tokens : &[Token], diagnostics : &mut Vec<Diagnostic>) -> Option<Axiom> {
     let mut pointer : usize = 0;
-    parse_axiom(&mut pointer, tokens)
+    match parse_axiom(&mut pointer, tokens, diagnostics) {
+        Ok(axiom) => Some(axiom),
+        Err(diagnostic) => {
+            diagnostics.push(diagnostic);
+            None
+        }
+    }
 }
 
-fn parse_axiom(pointer : &mut usize, tokens: &Vec<T>) -> Result<Axiom,String> {
+fn parse_axiom(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<Axiom,Diagnostic> {
     // BPM
-    expect(T::BpmKw, &tokens[*pointer], pointer)?;
+    expect(T::BpmKw, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("The code must start with the tempo, e.g. `bpm 90`"))?;
 
-    // BPM value
-    let bpm = to_u8(expect_value(&tokens[*pointer], pointer)?)?;
+    // BPM value, or ramp
+    let start = expect_bpm(&tokens[*pointer], pointer)?;
+    let end = if tokens[*pointer].kind == T::Arrow {
+        *pointer += 1;
+        expect_bpm(&tokens[*pointer], pointer)?
+    }
+    else {
+        start
+    };
+    let tempo = Tempo {start, end};
     expect(T::NewLine, &tokens[*pointer], pointer)?;
 
     // TIME SIGNATURE
-    let den : u8 = to_u8(expect_value(&tokens[*pointer], pointer)?)?;
+    let signature = parse_signature(pointer, tokens)
+        .map_err(|e| e.with_hint("The second line must be the time signature, e.g. `4/4`, `6/8` or `3+2+2/8`"))?;
+    expect(T::NewLine, &tokens[*pointer], pointer)?;
+
+    // OPTIONAL DIRECTIVES
+    let mut reference = None;
+    let mut tuning = None;
+    let mut key = None;
+    let mut song = None;
+    let mut swing = None;
+    loop {
+        let directive_start = *pointer;
+        let directive = match tokens[*pointer].kind {
+            T::ReferenceKw => parse_reference(pointer, tokens).map(|value| reference = Some(value)),
+            T::TuningKw => parse_tuning(pointer, tokens).map(|value| tuning = Some(value)),
+            T::KeyKw => parse_key(pointer, tokens).map(|value| key = Some(value)),
+            T::SongKw => parse_song(pointer, tokens).map(|value| song = Some(value)),
+            // Not a keyword, `swing` being also the name of a filter
+            T::String(ref name) if name == "swing" && matches!(tokens[*pointer+1].kind, T::Number(..) | T::Value(_) | T::String(_)) =>
+                parse_swing(pointer, tokens).map(|value| swing = Some(value)),
+            _ => break,
+        };
+        if let Err(diagnostic) = directive {
+            diagnostics.push(diagnostic);
+            *pointer = directive_start;
+            synchronize(pointer, tokens, true);
+            if tokens[*pointer].kind == T::NewLine {
+                *pointer += 1;
+            }
+        }
+    }
+
+    let mut blocks = parse_blocks(pointer, tokens, diagnostics);
+    while tokens[*pointer].kind != T::Eof {
+        diagnostics.push(Diagnostic::error("Unexpected `)`".to_string(), tokens[*pointer].span)
+            .with_hint("This parenthesis closes nothing"));
+        *pointer += 1;
+        blocks.append(&mut parse_blocks(pointer, tokens, diagnostics));
+    }
+
+    Ok(Axiom {tempo, signature, reference, tuning, key, song, swing, blocks})
+}
+
+/// `groups/denominator`, the groups being separated by `+`
+fn parse_signature(pointer : &mut usize, tokens: &[Token]) -> Result<Signature,Diagnostic> {
+    let mut groups = Vec::new();
+    loop {
+        let group = to_u8(expect_value(&tokens[*pointer], pointer)?, &tokens[*pointer-1])?;
+        if group == 0 {
+            return Err(Diagnostic::error("Empty time signature group".to_string(), tokens[*pointer-1].span));
+        }
+        groups.push(group);
+        if tokens[*pointer].kind != T::Plus {
+            break;
+        }
+        *pointer += 1;
+    }
     expect(T::Solidus, &tokens[*pointer], pointer)?;
-    let nom : u8 = to_u8(expect_value(&tokens[*pointer], pointer)?)?;
+    let denominator = to_u8(expect_value(&tokens[*pointer], pointer)?, &tokens[*pointer-1])?;
+    if !denominator.is_power_of_two() {
+        return Err(Diagnostic::error(format!("Invalid time signature denominator : {}", denominator), tokens[*pointer-1].span));
+    }
+
+    Ok(Signature {groups, denominator})
+}
+
+/// `reference <frequency of A4>`
+fn parse_reference(pointer : &mut usize, tokens: &[Token]) -> Result<f32,Diagnostic> {
+    expect(T::ReferenceKw, &tokens[*pointer], pointer)?;
+    let (frequency, unit) = expect_quantity(&tokens[*pointer], pointer)?;
+    if frequency <= 0. || !matches!(unit, None | Some(Unit::Hertz)) {
+        return Err(Diagnostic::error("The reference frequency must be positive".to_string(), tokens[*pointer-1].span)
+            .with_hint("e.g. `reference 432` or `reference 432hz`"));
+    }
+    expect(T::NewLine, &tokens[*pointer], pointer)?;
+
+    Ok(frequency as f32)
+}
+
+/// `tuning <name>`, e.g. `tuning 31edo` or `tuning just`
+fn parse_tuning(pointer : &mut usize, tokens: &[Token]) -> Result<TuningName,Diagnostic> {
+    expect(T::TuningKw, &tokens[*pointer], pointer)?;
+    let start = tokens[*pointer].span;
+    let name = match &tokens[*pointer].kind {
+        T::Value(divisions) => {
+            *pointer += 1;
+            format!("{}{}", divisions, expect_string(&tokens[*pointer], pointer)?)
+        },
+        _ => expect_string(&tokens[*pointer], pointer)
+            .map_err(|e| e.with_hint("e.g. `tuning 31edo` or `tuning just`"))?,
+    };
+    let span = start.to(tokens[*pointer-1].span);
+    expect(T::NewLine, &tokens[*pointer], pointer)?;
+
+    Ok(TuningName {name, span})
+}
+
+/// `key <tonic> <scale>`, e.g. `key D minor` or `key F# dorian`
+fn parse_key(pointer : &mut usize, tokens: &[Token]) -> Result<Key,Diagnostic> {
+    expect(T::KeyKw, &tokens[*pointer], pointer)?;
+    let tonic = match &tokens[*pointer].kind {
+        T::PitchClass(pitch_class) => *pitch_class,
+        T::String(name) => match &name[..] {
+            "C" => 0,
+            "D" => 2,
+            "E" => 4,
+            "F" => 5,
+            "G" => 7,
+            "A" => 9,
+            "B" => 11,
+            _ => return Err(Diagnostic::error(format!("Invalid tonic : {}", name), tokens[*pointer].span)
+                .with_hint("The tonic is a note name without octave, e.g. `key F# minor`")),
+        },
+        _ => return Err(Diagnostic::error(format!("Expected tonic, found {:?}", tokens[*pointer].kind), tokens[*pointer].span)
+            .with_hint("e.g. `key D minor`")),
+    };
+    *pointer += 1;
+    let name = expect_string(&tokens[*pointer], pointer)?;
+    let scale = scale_intervals(&name).ok_or_else(|| Diagnostic::error(
+        format!("Unknown scale : {}", name),
+        tokens[*pointer-1].span,
+    ).with_hint("Known scales are major, minor, the modes, harmonic_minor, melodic_minor, pentatonic, minor_pentatonic, blues, whole_tone and chromatic"))?;
+    expect(T::NewLine, &tokens[*pointer], pointer)?;
+
+    Ok(Key {tonic, scale})
+}
+
+/// `swing 60%` or `swing mpc16`
+fn parse_swing(pointer : &mut usize, tokens: &[Token]) -> Result<Groove,Diagnostic> {
+    expect_string(&tokens[*pointer], pointer)?;
+    let token = &tokens[*pointer];
+    let groove = match &token.kind {
+        T::String(name) => groove_template(name).ok_or_else(|| Diagnostic::error(format!("Unknown groove : {}", name), token.span)
+            .with_hint("Known grooves are mpc8, mpc16, shuffle and laid_back"))?,
+        T::Number(percent, Some(Unit::Percent)) => Groove::swing(*percent as f32 / 100.)
+            .ok_or_else(|| Diagnostic::error("Swing is between 50% and 75%".to_string(), token.span)
+                .with_hint("50% is straight and 66% a triplet feel"))?,
+        _ => return Err(Diagnostic::error("Swing is written in percent".to_string(), token.span)
+            .with_hint("e.g. `swing 60%`")),
+    };
+    *pointer += 1;
     expect(T::NewLine, &tokens[*pointer], pointer)?;
-    let signature = (den,nom);
 
-    let blocks = parse_blocks(pointer, tokens)?;
+    Ok(groove)
+}
+
+/// `song section ...`, `name*n` repeating a section `n` times, e.g.
+/// `song A*2 B A`
+fn parse_song(pointer : &mut usize, tokens: &[Token]) -> Result<Vec<Reference>,Diagnostic> {
+    expect(T::SongKw, &tokens[*pointer], pointer)?;
+    let mut sections = Vec::new();
+    while !matches!(tokens[*pointer].kind, T::NewLine | T::Eof) {
+        let span = tokens[*pointer].span;
+        let name = expect_string(&tokens[*pointer], pointer)
+            .map_err(|e| e.with_hint("The song lists the names of the sections, e.g. `song A A B A` or `song A*2 B`"))?;
+        let count = if tokens[*pointer].kind == T::Star {
+            *pointer += 1;
+            let count = to_u8(expect_value(&tokens[*pointer], pointer)?, &tokens[*pointer-1])?;
+            if count == 0 {
+                return Err(Diagnostic::error("A section is played at least once".to_string(), tokens[*pointer-1].span));
+            }
+            count
+        }
+        else {
+            1
+        };
+        let span = span.to(tokens[*pointer-1].span);
+        sections.extend((0..count).map(|_| Reference {name : name.clone(), span}));
+    }
+    if sections.is_empty() {
+        return Err(Diagnostic::error("The song has no section".to_string(), tokens[*pointer].span)
+            .with_hint("e.g. `song A A B A`"));
+    }
+    expect(T::NewLine, &tokens[*pointer], pointer)?;
 
-    Ok(Axiom {bpm, signature, blocks})
+    Ok(sections)
 }
 
-fn parse_blocks(pointer: &mut usize, tokens: &Vec<T>) -> Result<Vec<Block>,String> {
+/// Parses blocks up to the end of the enclosing block. A block with an error
+/// is reported and skipped (see `synchronize`).
+fn parse_blocks(pointer: &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Vec<Block> {
     let mut blocks= Vec::new();
 
     'main_loop : loop {
-        match tokens.get(*pointer) {
-            Some(T::LeftABracket | T::String(_) | T::LeftParenthesis) => blocks.push(parse_block(pointer, tokens)?),
-            Some(T::RightParenthesis) => break 'main_loop,
-            Some(T::NewLine) => *pointer += 1,
-            None => break 'main_loop,
-            Some(x) => return Err(format!("Expected '<', instrument name or end of file, found {:?}",*x))
+        let block_start = *pointer;
+        match &tokens[*pointer].kind {
+            T::LeftABracket | T::String(_) | T::LeftParenthesis | T::LetKw | T::DefKw | T::SectionKw => match parse_block(pointer, tokens, diagnostics) {
+                Ok(block) => blocks.push(block),
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                    // Blocks spanning several lines have a `(` at the end of their first line
+                    let single_line = match tokens[block_start].kind {
+                        T::String(_) => true,
+                        T::LetKw | T::DefKw => {
+                            let line_end = tokens[block_start..].iter()
+                                .position(|token| matches!(token.kind, T::NewLine | T::Eof))
+                                .unwrap() + block_start;
+                            tokens[line_end - 1].kind != T::LeftParenthesis
+                        },
+                        _ => false,
+                    };
+                    *pointer = block_start;
+                    synchronize(pointer, tokens, single_line);
+                }
+            },
+            T::RightParenthesis | T::Eof => break 'main_loop,
+            T::NewLine => *pointer += 1,
+            T::SongKw => {
+                diagnostics.push(Diagnostic::error("Unexpected `song`".to_string(), tokens[*pointer].span)
+                    .with_hint("The song is written among the directives, before the blocks"));
+                synchronize(pointer, tokens, true);
+            },
+            x => {
+                diagnostics.push(Diagnostic::error(
+                    format!("Expected '<', instrument name or end of file, found {:?}",*x),
+                    tokens[*pointer].span,
+                ));
+                synchronize(pointer, tokens, true);
+            }
         }
     }
 
-    Ok(blocks)
+    blocks
+}
+
+/// Skips tokens up to the next new line or to the parenthesis closing the
+/// enclosing block. Unless `single_line`, new lines and parentheses within
+/// parentheses opened on the way are skipped too.
+fn synchronize(pointer : &mut usize, tokens : &[Token], single_line : bool) {
+    let mut depth : usize = 0;
+    loop {
+        match tokens[*pointer].kind {
+            T::Eof => return,
+            T::NewLine if depth == 0 || single_line => return,
+            T::RightParenthesis if depth == 0 => return,
+            T::RightParenthesis => depth -= 1,
+            T::LeftParenthesis => depth += 1,
+            _ => {},
+        }
+        *pointer += 1;
+    }
 }
 
-fn parse_block(pointer : &mut usize, tokens: &Vec<T>) -> Result<Block,String> {
-    match tokens[*pointer] {
-        T::LeftABracket | T::LeftParenthesis => Ok(Block::Recursive(parse_recblock(pointer, tokens)?)),
+fn parse_block(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<Block,Diagnostic> {
+    match tokens[*pointer].kind {
+        T::LeftABracket | T::LeftParenthesis => Ok(Block::Recursive(parse_recblock(pointer, tokens, diagnostics)?)),
+        T::String(_) if matches!(tokens[*pointer+1].kind, T::NewLine | T::Eof | T::RightParenthesis) => {
+            let reference = Reference {name : expect_string(&tokens[*pointer], pointer)?, span : tokens[*pointer-1].span};
+            if tokens[*pointer].kind == T::NewLine {
+                *pointer += 1;
+            }
+            Ok(Block::Reference(reference))
+        },
         T::String(_) => Ok(Block::Instrument(parse_instrument(pointer, tokens)?)),
-        _ => Err(format!("Expected left angled-bracket or instrument name (block), found {:?}",tokens[*pointer]))
+        T::LetKw => Ok(Block::Let(parse_let(pointer, tokens, diagnostics)?)),
+        T::DefKw => Ok(Block::Def(parse_def(pointer, tokens, diagnostics)?)),
+        T::SectionKw => Ok(Block::Section(parse_section(pointer, tokens, diagnostics)?)),
+        _ => Err(Diagnostic::error(
+            format!("Expected left angled-bracket or instrument name (block), found {:?}",tokens[*pointer].kind),
+            tokens[*pointer].span,
+        )),
     }
 }
 
-fn parse_recblock(pointer : &mut usize, tokens: &Vec<T>) -> Result<RecBlock,String> {
-    let filters;
-    if tokens[*pointer] == T::LeftABracket {
-        filters = parse_filter_list(pointer, tokens)?;
+fn parse_recblock(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<RecBlock,Diagnostic> {
+    let filters = if tokens[*pointer].kind == T::LeftABracket {
+        parse_filter_list(pointer, tokens)?
     }
     else {
-        filters = vec![];
-    }
+        vec![]
+    };
     expect(T::LeftParenthesis, &tokens[*pointer], pointer)?;
-    let blocks = parse_blocks(pointer, tokens)?;
+    let blocks = parse_blocks(pointer, tokens, diagnostics);
+    expect(T::RightParenthesis, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("A block is missing its closing parenthesis"))?;
 
     Ok(RecBlock {filters, blocks})
 }
 
-fn parse_instrument(pointer : &mut usize, tokens: &Vec<T>) -> Result<Instrument,String> {
+/// `section name (blocks)`
+fn parse_section(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<Section,Diagnostic> {
+    expect(T::SectionKw, &tokens[*pointer], pointer)?;
+    let span = tokens[*pointer].span;
+    let name = expect_string(&tokens[*pointer], pointer)?;
+    expect(T::LeftParenthesis, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("Sections are written `section name (`, their blocks on the next lines, then `)`"))?;
+    let blocks = parse_blocks(pointer, tokens, diagnostics);
+    expect(T::RightParenthesis, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("A section is missing its closing parenthesis"))?;
+
+    Ok(Section {name, span, blocks})
+}
+
+fn parse_instrument(pointer : &mut usize, tokens: &[Token]) -> Result<Instrument,Diagnostic> {
+    let start = tokens[*pointer].span;
     let instrument = expect_string(&tokens[*pointer],pointer)?;
-    let filters;
-    if tokens[*pointer] == T::LeftABracket {
-        filters = parse_filter_list(pointer, tokens)?;
+    let filters = if tokens[*pointer].kind == T::LeftABracket {
+        parse_filter_list(pointer, tokens)?
     }
     else {
-        filters = vec![];
-    }
+        vec![]
+    };
     let notes = parse_notes(pointer, tokens)?;
-    expect(T::NewLine, &tokens[*pointer], pointer)?;
+    let span = start.to(tokens[*pointer-1].span);
+    expect(T::NewLine, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("Each instrument goes on its own line"))?;
+
+    Ok(Instrument {filters, instrument, notes, span})
+}
 
-    Ok(Instrument {filters, instrument, notes})
+/// `let name = value`, the value being a note list `(note, ...)`, a filter
+/// chain `<filter, ...>` or a block
+fn parse_let(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<Let,Diagnostic> {
+    expect(T::LetKw, &tokens[*pointer], pointer)?;
+    let span = tokens[*pointer].span;
+    let name = expect_string(&tokens[*pointer], pointer)?;
+    if parse_numeral(&name).is_some() {
+        return Err(Diagnostic::error(format!("`{}` is a chord symbol and cannot be bound", name), span));
+    }
+    expect(T::Equals, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("Bindings are written `let name = value`"))?;
+    let value = parse_bound_value(pointer, tokens, diagnostics)?;
+
+    Ok(Let {name, span, value})
 }
 
-fn parse_notes(pointer : &mut usize, tokens : &Vec<T>) -> Result<Vec<Note>, String> {
+/// `def name(param, ...) = value`, the value being a note list or a block
+fn parse_def(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<Def,Diagnostic> {
+    expect(T::DefKw, &tokens[*pointer], pointer)?;
+    let span = tokens[*pointer].span;
+    let name = expect_string(&tokens[*pointer], pointer)?;
+    if parse_numeral(&name).is_some() {
+        return Err(Diagnostic::error(format!("`{}` is a chord symbol and cannot be bound", name), span));
+    }
+    if name == "rand" {
+        return Err(Diagnostic::error("`rand` is built in and cannot be redefined".to_string(), span));
+    }
+    expect(T::LeftParenthesis, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("Functions are written `def name(param, ...) = value`"))?;
+    let mut params = Vec::new();
+    while tokens[*pointer].kind != T::RightParenthesis {
+        if !params.is_empty() {
+            expect(T::Comma, &tokens[*pointer], pointer)?;
+        }
+        params.push(expect_string(&tokens[*pointer], pointer)?);
+    }
+    *pointer += 1;
+    expect(T::Equals, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("Functions are written `def name(param, ...) = value`"))?;
+    let value_start = tokens[*pointer].span;
+    let body = match parse_bound_value(pointer, tokens, diagnostics)? {
+        LetValue::Filters(_) => return Err(Diagnostic::error("Functions return a note list or a block".to_string(), value_start)),
+        body => body,
+    };
+
+    Ok(Def {name, params, span, body})
+}
+
+/// Value of a `let` or `def`: a note list `(note, ...)`, a filter chain
+/// `<filter, ...>` or a block, with its new line
+fn parse_bound_value(pointer : &mut usize, tokens: &[Token], diagnostics : &mut Vec<Diagnostic>) -> Result<LetValue,Diagnostic> {
+    let end_line = |pointer : &mut usize| match tokens[*pointer].kind {
+        T::Eof => Ok(()),
+        _ => expect(T::NewLine, &tokens[*pointer], pointer),
+    };
+
+    Ok(match tokens[*pointer].kind {
+        T::LeftParenthesis if tokens[*pointer+1].kind != T::NewLine => {
+            let notes = parse_notes(pointer, tokens)?;
+            end_line(pointer)?;
+            LetValue::Notes(notes)
+        },
+        T::LeftABracket => {
+            let start = *pointer;
+            let filters = parse_filter_list(pointer, tokens)?;
+            if matches!(tokens[*pointer].kind, T::NewLine | T::Eof) {
+                end_line(pointer)?;
+                LetValue::Filters(filters)
+            }
+            else {
+                *pointer = start;
+                LetValue::Block(Box::new(parse_block(pointer, tokens, diagnostics)?))
+            }
+        },
+        T::LetKw | T::DefKw => return Err(Diagnostic::error(
+            format!("Expected a value, found {:?}", tokens[*pointer].kind),
+            tokens[*pointer].span,
+        )),
+        _ => LetValue::Block(Box::new(parse_block(pointer, tokens, diagnostics)?)),
+    })
+}
+
+fn parse_notes(pointer : &mut usize, tokens : &[Token]) -> Result<Vec<Note>, Diagnostic> {
     expect(T::LeftParenthesis,&tokens[*pointer], pointer)?;
     let mut notes = Vec::new();
 
     notes.push(parse_note(pointer, tokens)?);
-    while tokens[*pointer] != T::RightParenthesis {
+    while tokens[*pointer].kind != T::RightParenthesis {
         expect(T::Comma, &tokens[*pointer], pointer)?;
         notes.push(parse_note(pointer,tokens)?);
     }
@@ -141,18 +762,21 @@ fn parse_notes(pointer : &mut usize, tokens : &Vec<T>) -> Result<Vec<Note>, Stri
     Ok(notes)
 }
 
-fn parse_filter_list(pointer : &mut usize, tokens: &Vec<T>) -> Result<Vec<Filter>,String> {
+fn parse_filter_list(pointer : &mut usize, tokens: &[Token]) -> Result<Vec<Filter>,Diagnostic> {
     
     expect(T::LeftABracket, &tokens[*pointer], pointer)?;
 
     let mut filter_list = Vec::new();
-    'main_loop : while tokens[*pointer] != T::RightABracket {
+    'main_loop : while tokens[*pointer].kind != T::RightABracket {
         filter_list.push(parse_filter(pointer, tokens)?);
-        if tokens[*pointer] != T::Comma {
-            if tokens[*pointer] == T::RightABracket {
+        if tokens[*pointer].kind != T::Comma {
+            if tokens[*pointer].kind == T::RightABracket {
                 break 'main_loop;
             }
-            return Err(format!("Expected comma or right angled-bracket, found {:?}",tokens[*pointer]));
+            return Err(Diagnostic::error(
+                format!("Expected comma or right angled-bracket, found {:?}",tokens[*pointer].kind),
+                tokens[*pointer].span,
+            ));
         }
         *pointer += 1;
     }
@@ -161,58 +785,466 @@ fn parse_filter_list(pointer : &mut usize, tokens: &Vec<T>) -> Result<Vec<Filter
     Ok(filter_list)
 }
 
-fn parse_filter(pointer : &mut usize, tokens: &Vec<T>) -> Result<Filter,String> {
+/// `name`, or `name:arg arg ...`
+fn parse_filter(pointer : &mut usize, tokens: &[Token]) -> Result<Filter,Diagnostic> {
+    let start = tokens[*pointer].span;
     let name = expect_string(&tokens[*pointer], pointer)?;
-    expect(T::Colon, &tokens[*pointer], pointer)?;
-    let value = expect_value(&tokens[*pointer], pointer)?;
-    Ok(Filter {name, value})
+    let mut args = Vec::new();
+    if tokens[*pointer].kind == T::Colon {
+        *pointer += 1;
+        args.push(parse_filter_arg(pointer, tokens)?);
+        while !matches!(tokens[*pointer].kind, T::Comma | T::RightABracket) {
+            args.push(parse_filter_arg(pointer, tokens)?);
+        }
+    }
+
+    Ok(Filter {name, args, span : start.to(tokens[*pointer-1].span)})
+}
+
+/// `value` or `name=value`
+fn parse_filter_arg(pointer : &mut usize, tokens: &[Token]) -> Result<FilterArg,Diagnostic> {
+    let name = match (&tokens[*pointer].kind, tokens.get(*pointer+1).map(|token| &token.kind)) {
+        (T::String(name), Some(T::Equals)) => {
+            *pointer += 2;
+            Some(name.clone())
+        },
+        _ => None,
+    };
+    let value = parse_filter_value(pointer, tokens)?;
+
+    Ok(FilterArg {name, value})
+}
+
+fn parse_filter_value(pointer : &mut usize, tokens: &[Token]) -> Result<FilterValue,Diagnostic> {
+    let value = match &tokens[*pointer].kind {
+        T::Value(_) | T::Number(..) => {
+            let (value, unit) = expect_quantity(&tokens[*pointer], pointer)?;
+            return Ok(FilterValue::Number(value, unit));
+        },
+        // After another number, the lexer reads the sign as a hold
+        T::Dash if matches!(tokens.get(*pointer+1).map(|token| &token.kind), Some(T::Value(_) | T::Number(..))) => {
+            *pointer += 1;
+            let (value, unit) = expect_quantity(&tokens[*pointer], pointer)?;
+            return Ok(FilterValue::Number(-value, unit));
+        },
+        T::LeftSBracket => {
+            *pointer += 1;
+            let mut values = Vec::new();
+            while tokens[*pointer].kind != T::RightSBracket {
+                if !values.is_empty() && tokens[*pointer].kind == T::Comma {
+                    *pointer += 1;
+                }
+                values.push(parse_filter_value(pointer, tokens)
+                    .map_err(|e| e.with_hint("A list ends with `]`"))?);
+            }
+            FilterValue::List(values)
+        },
+        T::Text(text) => FilterValue::Text(text.clone()),
+        T::String(name) if name == "bar" && matches!(
+            tokens.get(*pointer+1).map(|token| &token.kind),
+            Some(T::PercentSign | T::DoubleEquals | T::NotEquals),
+        ) => return Ok(FilterValue::Condition(parse_condition(pointer, tokens)?)),
+        T::String(name) => FilterValue::Ident(name.clone()),
+        _ => return Err(Diagnostic::error(format!("Expected filter argument, found {:?}", tokens[*pointer].kind), tokens[*pointer].span)
+            .with_hint("Filters are written `name:value`, e.g. `lp:800hz`")),
+    };
+    *pointer += 1;
+
+    Ok(value)
 }
 
-fn parse_note(pointer : &mut usize, tokens: &Vec<T>) -> Result<Note,String> {
-    if let T::Value(val) = tokens[*pointer] {
+/// `bar%period==value` or `bar%period!=value`, the period being optional
+fn parse_condition(pointer : &mut usize, tokens: &[Token]) -> Result<Condition,Diagnostic> {
+    *pointer += 1;
+    let natural = |pointer : &mut usize| -> Result<u32, Diagnostic> {
+        let value = expect_value(&tokens[*pointer], pointer)?;
+        value.try_into().map_err(|_| Diagnostic::error(format!("Invalid bar number : {}", value), tokens[*pointer-1].span))
+    };
+    let period = if tokens[*pointer].kind == T::PercentSign {
         *pointer += 1;
-        let pitch = val.try_into().unwrap();
-        return Ok(Note {pitch});
+        match natural(pointer)? {
+            0 => return Err(Diagnostic::error("The period must be positive".to_string(), tokens[*pointer-1].span)),
+            period => Some(period),
+        }
+    }
+    else {
+        None
+    };
+    let negated = match tokens[*pointer].kind {
+        T::DoubleEquals => false,
+        T::NotEquals => true,
+        _ => return Err(Diagnostic::error(format!("Expected `==` or `!=`, found {:?}", tokens[*pointer].kind), tokens[*pointer].span)
+            .with_hint("Conditions compare the bar number, e.g. `bar%8==7`")),
+    };
+    *pointer += 1;
+    let value = natural(pointer)?;
+
+    Ok(Condition {period, negated, value})
+}
+
+fn parse_note(pointer : &mut usize, tokens: &[Token]) -> Result<Note,Diagnostic> {
+    let kind = match &tokens[*pointer].kind {
+        T::LeftSBracket => NoteKind::Group(parse_group(pointer, tokens)?),
+        T::LeftBrace => NoteKind::Chord(parse_chord(pointer, tokens)?),
+        T::String(name) if name == "rand" && tokens[*pointer+1].kind == T::LeftParenthesis => {
+            let call = parse_call(pointer, tokens)?;
+            let args : Result<[Expr; 2], Vec<Expr>> = call.args.try_into();
+            match args {
+                Ok([low, high]) => NoteKind::Random(low, high),
+                Err(args) => return Err(Diagnostic::error(format!("`rand` takes 2 arguments, found {}", args.len()), call.span)
+                    .with_hint("e.g. `rand(C4, C5)`")),
+            }
+        },
+        T::String(_) if tokens[*pointer+1].kind == T::LeftParenthesis => NoteKind::Call(parse_call(pointer, tokens)?),
+        T::String(name) if parse_numeral(name).is_some() => {
+            *pointer += 1;
+            NoteKind::ChordDegree(parse_numeral(name).unwrap())
+        },
+        T::LeftParenthesis if is_alternation(*pointer, tokens) => NoteKind::Alternation(parse_alternation(pointer, tokens)?),
+        T::Value(_) | T::NoteName(_) | T::String(_) | T::LeftParenthesis => match parse_expr(pointer, tokens)? {
+            Expr::Value(key) => NoteKind::Pitch(Pitch(key)),
+            Expr::Name(reference) => NoteKind::Reference(reference),
+            expr => NoteKind::Expr(expr),
+        },
+        _ => {
+            let kind = match &tokens[*pointer].kind {
+                T::ChordName(root, quality) => {
+                    let intervals = chord_intervals(quality).ok_or_else(|| Diagnostic::error(
+                        format!("Unknown chord quality : {}", quality),
+                        tokens[*pointer].span,
+                    ).with_hint("Known qualities are maj, m, dim, aug, sus2, sus4, maj7, m7, dom7, dim7 and m7b5"))?;
+                    NoteKind::Chord(intervals.iter().map(|interval| Pitch(root + interval)).collect())
+                },
+                T::Caret => {
+                    *pointer += 1;
+                    NoteKind::Degree(parse_degree(&tokens[*pointer])?)
+                },
+                T::Underscore | T::Dot => NoteKind::Rest,
+                T::Dash => NoteKind::Hold,
+                _ => return Err(Diagnostic::error(format!("Expected note, found {:?}",tokens[*pointer].kind), tokens[*pointer].span)),
+            };
+            *pointer += 1;
+            kind
+        }
+    };
+
+    let kind = if tokens[*pointer].kind == T::Colon {
+        NoteKind::Euclid(parse_euclid(Note::new(kind), pointer, tokens)?)
     }
     else {
-        return Err(format!("Expected note, found {:?}",tokens[*pointer]));
+        kind
+    };
+
+    let kind = if tokens[*pointer].kind == T::Question {
+        NoteKind::Maybe(Box::new(Note::new(kind)), parse_probability(pointer, tokens)?)
     }
+    else {
+        kind
+    };
+
+    let mut weight = 1;
+    if tokens[*pointer].kind == T::At {
+        *pointer += 1;
+        let value = expect_value(&tokens[*pointer], pointer)?;
+        let positive : Result<u32, _> = value.try_into();
+        weight = match positive {
+            Ok(weight) if weight > 0 => weight,
+            _ => return Err(Diagnostic::error(format!("Invalid note weight : {}", value), tokens[*pointer-1].span)
+                .with_hint("Weights are positive integers, e.g. `C4@2`")),
+        };
+    }
+
+    Ok(Note {kind, weight})
 }
 
-fn expect(expected_token : T, token : &T, pointer : &mut usize) -> Result<(),String> {
-    if expected_token == *token {
+/// Whether the parenthesis at `start` opens an alternation rather than an
+/// expression, that is if a `|` is directly within it.
+fn is_alternation(start : usize, tokens : &[Token]) -> bool {
+    let mut depth : usize = 0;
+    for token in &tokens[start..] {
+        match token.kind {
+            T::LeftParenthesis | T::LeftSBracket | T::LeftBrace => depth += 1,
+            T::RightParenthesis | T::RightSBracket | T::RightBrace if depth <= 1 => return false,
+            T::RightParenthesis | T::RightSBracket | T::RightBrace => depth -= 1,
+            T::Pipe if depth == 1 => return true,
+            T::NewLine | T::Eof => return false,
+            _ => {},
+        }
+    }
+
+    false
+}
+
+/// `(note | note | ...)`, the weight going after the parenthesis
+fn parse_alternation(pointer : &mut usize, tokens: &[Token]) -> Result<Vec<Note>,Diagnostic> {
+    expect(T::LeftParenthesis, &tokens[*pointer], pointer)?;
+    let mut notes = Vec::new();
+    loop {
+        let start = tokens[*pointer].span;
+        let note = parse_note(pointer, tokens)?;
+        if note.weight != 1 {
+            return Err(Diagnostic::error("Alternatives have no weight".to_string(), start.to(tokens[*pointer-1].span))
+                .with_hint("The weight goes after the alternation, e.g. `(1 | 5)@2`"));
+        }
+        notes.push(note);
+        if tokens[*pointer].kind != T::Pipe {
+            break;
+        }
+        *pointer += 1;
+    }
+    expect(T::RightParenthesis, &tokens[*pointer], pointer)
+        .map_err(|e| e.with_hint("Alternatives are separated by `|`, e.g. `(1 | 5 | 7)`"))?;
+
+    Ok(notes)
+}
+
+/// `:euclid(hits, steps)` or `:euclid(hits, steps, rotation)` after `note`
+fn parse_euclid(note : Note, pointer : &mut usize, tokens: &[Token]) -> Result<Euclid,Diagnostic> {
+    expect(T::Colon, &tokens[*pointer], pointer)?;
+    let call = parse_call(pointer, tokens)?;
+    if call.name != "euclid" {
+        return Err(Diagnostic::error(format!("Unknown rhythm : {}", call.name), call.span)
+            .with_hint("e.g. `C2:euclid(3, 8)`"));
+    }
+    let (span, count) = (call.span, call.args.len());
+    let mut args = call.args.into_iter();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(hits), Some(steps), rotation, None) => Ok(Euclid {
+            note : Box::new(note),
+            hits,
+            steps,
+            rotation : rotation.unwrap_or(Expr::Value(0)),
+            span,
+        }),
+        _ => Err(Diagnostic::error(format!("`euclid` takes 2 or 3 arguments, found {}", count), span)
+            .with_hint("e.g. `C2:euclid(3, 8)` or `C2:euclid(3, 8, 2)`")),
+    }
+}
+
+/// `?`, directly followed by an optional probability, `0.5` by default
+fn parse_probability(pointer : &mut usize, tokens: &[Token]) -> Result<f32,Diagnostic> {
+    let question = tokens[*pointer].span;
+    *pointer += 1;
+    // A number after a space is the next note of a group
+    if tokens[*pointer].span.start != question.end || !matches!(tokens[*pointer].kind, T::Value(_) | T::Number(..)) {
+        return Ok(0.5);
+    }
+    let probability = match expect_quantity(&tokens[*pointer], pointer)? {
+        (value, Some(Unit::Percent)) => value / 100.,
+        (value, None) => value,
+        (_, Some(unit)) => return Err(Diagnostic::error(format!("A probability cannot be in {}", unit), tokens[*pointer-1].span)),
+    };
+    if !(0. ..=1.).contains(&probability) {
+        return Err(Diagnostic::error("Probabilities are between 0 and 1".to_string(), tokens[*pointer-1].span)
+            .with_hint("e.g. `C4?0.3` or `C4?30%`"));
+    }
+
+    Ok(probability as f32)
+}
+
+/// `name(arg, ...)`
+fn parse_call(pointer : &mut usize, tokens: &[Token]) -> Result<Call,Diagnostic> {
+    let start = tokens[*pointer].span;
+    let name = expect_string(&tokens[*pointer], pointer)?;
+    expect(T::LeftParenthesis, &tokens[*pointer], pointer)?;
+    let mut args = Vec::new();
+    while tokens[*pointer].kind != T::RightParenthesis {
+        if !args.is_empty() {
+            expect(T::Comma, &tokens[*pointer], pointer)?;
+        }
+        args.push(parse_expr(pointer, tokens)?);
+    }
+    *pointer += 1;
+
+    Ok(Call {name, args, span : start.to(tokens[*pointer-1].span)})
+}
+
+/// `term`, `expr + term` or `expr - term`. In note lists, a `-` is a
+/// subtraction only when written right after its left operand, as in
+/// `root-3`, and a hold otherwise.
+fn parse_expr(pointer : &mut usize, tokens: &[Token]) -> Result<Expr,Diagnostic> {
+    let mut expr = parse_term(pointer, tokens)?;
+    loop {
+        let operator = match tokens[*pointer].kind {
+            T::Plus => Operator::Add,
+            T::Dash if tokens[*pointer].span.start == tokens[*pointer-1].span.end
+                && matches!(tokens[*pointer+1].kind, T::Value(_) | T::NoteName(_) | T::String(_) | T::LeftParenthesis) => Operator::Sub,
+            _ => return Ok(expr),
+        };
+        let span = tokens[*pointer].span;
+        *pointer += 1;
+        let right = parse_term(pointer, tokens)?;
+        expr = Expr::Binary(Box::new(expr), operator, Box::new(right), span);
+    }
+}
+
+/// `atom`, `term * atom` or `term / atom`
+fn parse_term(pointer : &mut usize, tokens: &[Token]) -> Result<Expr,Diagnostic> {
+    let mut expr = parse_atom(pointer, tokens)?;
+    loop {
+        let operator = match tokens[*pointer].kind {
+            T::Star => Operator::Mul,
+            T::Solidus => Operator::Div,
+            _ => return Ok(expr),
+        };
+        let span = tokens[*pointer].span;
+        *pointer += 1;
+        let right = parse_atom(pointer, tokens)?;
+        expr = Expr::Binary(Box::new(expr), operator, Box::new(right), span);
+    }
+}
+
+/// Number, note name, parameter name or `(expr)`
+fn parse_atom(pointer : &mut usize, tokens: &[Token]) -> Result<Expr,Diagnostic> {
+    let token = &tokens[*pointer];
+    let expr = match &token.kind {
+        T::Value(val) => Expr::Value(to_i32(*val, token)?),
+        T::NoteName(key) => Expr::Value(*key),
+        T::String(name) => Expr::Name(Reference {name : name.clone(), span : token.span}),
+        T::LeftParenthesis => {
+            *pointer += 1;
+            let expr = parse_expr(pointer, tokens)?;
+            expect(T::RightParenthesis, &tokens[*pointer], pointer)?;
+            return Ok(expr);
+        },
+        _ => return Err(Diagnostic::error(format!("Expected pitch, found {:?}", token.kind), token.span)),
+    };
+    *pointer += 1;
+
+    Ok(expr)
+}
+
+/// MIDI key number or note name
+fn parse_pitch(token : &Token) -> Result<Pitch,Diagnostic> {
+    match token.kind {
+        T::Value(val) => Ok(Pitch(to_i32(val, token)?)),
+        T::NoteName(key) => Ok(Pitch(key)),
+        _ => Err(Diagnostic::error(format!("Expected pitch, found {:?}", token.kind), token.span)),
+    }
+}
+
+/// Number of a scale degree, after `^`
+fn parse_degree(token : &Token) -> Result<u32,Diagnostic> {
+    let value = match token.kind {
+        T::Value(value) => value,
+        _ => return Err(Diagnostic::error(format!("Expected scale degree, found {:?}", token.kind), token.span)),
+    };
+    let degree : Result<u32, _> = value.try_into();
+    match degree {
+        Ok(degree) if degree > 0 => Ok(degree),
+        _ => Err(Diagnostic::error(format!("Invalid scale degree : {}", value), token.span)
+            .with_hint("Degrees are counted from 1, the tonic being `^1`")),
+    }
+}
+
+/// `{pitch pitch ...}`, commas between the pitches being optional
+fn parse_chord(pointer : &mut usize, tokens: &[Token]) -> Result<Vec<Pitch>,Diagnostic> {
+    expect(T::LeftBrace, &tokens[*pointer], pointer)?;
+    let mut pitches = Vec::new();
+    while tokens[*pointer].kind != T::RightBrace {
+        if !pitches.is_empty() && tokens[*pointer].kind == T::Comma {
+            *pointer += 1;
+        }
+        pitches.push(parse_pitch(&tokens[*pointer])
+            .map_err(|e| e.with_hint("A chord ends with `}`"))?);
+        *pointer += 1;
+    }
+    if pitches.is_empty() {
+        return Err(Diagnostic::error("Empty chord".to_string(), tokens[*pointer].span)
+            .with_hint("Use `_` for a rest"));
+    }
+    *pointer += 1;
+
+    Ok(pitches)
+}
+
+/// `[note note ...]`, commas between the notes being optional
+fn parse_group(pointer : &mut usize, tokens: &[Token]) -> Result<Vec<Note>,Diagnostic> {
+    expect(T::LeftSBracket, &tokens[*pointer], pointer)?;
+    let mut notes = vec![parse_note(pointer, tokens)?];
+    while tokens[*pointer].kind != T::RightSBracket {
+        if tokens[*pointer].kind == T::Comma {
+            *pointer += 1;
+        }
+        notes.push(parse_note(pointer, tokens)
+            .map_err(|e| e.with_hint("A group ends with `]`"))?);
+    }
+    *pointer += 1;
+
+    Ok(notes)
+}
+
+fn expect(expected_token : T, token : &Token, pointer : &mut usize) -> Result<(),Diagnostic> {
+    if expected_token == token.kind {
         *pointer += 1;
         Ok(())
     }
     else {
-        return Err(format!("Expected {:?}, found {:?}", expected_token, token));
+        Err(Diagnostic::error(format!("Expected {:?}, found {:?}", expected_token, token.kind), token.span))
     }
 }
 
-fn expect_value(token : &T, pointer : &mut usize) -> Result<isize, String> {
-    if let T::Value(val) = token {
+fn expect_value(token : &Token, pointer : &mut usize) -> Result<isize, Diagnostic> {
+    if let T::Value(val) = token.kind {
         *pointer += 1;
-        Ok(*val)
+        Ok(val)
     }
     else {
-        Err(format!("Expected number value, found {:?}", token))
+        Err(Diagnostic::error(format!("Expected number value, found {:?}", token.kind), token.span))
+    }
+}
+
+/// Number with its unit, if any
+fn expect_quantity(token : &Token, pointer : &mut usize) -> Result<(f64, Option<Unit>), Diagnostic> {
+    let quantity = match token.kind {
+        T::Value(val) => (val as f64, None),
+        T::Number(val, unit) => (val, unit),
+        _ => return Err(Diagnostic::error(format!("Expected number value, found {:?}", token.kind), token.span)),
+    };
+    *pointer += 1;
+    Ok(quantity)
+}
+
+/// Number without unit
+fn expect_number(token : &Token, pointer : &mut usize) -> Result<f64, Diagnostic> {
+    match expect_quantity(token, pointer)? {
+        (value, None) => Ok(value),
+        (_, Some(unit)) => {
+            *pointer -= 1;
+            Err(Diagnostic::error(format!("Unexpected unit : {}", unit), token.span))
+        },
+    }
+}
+
+/// Tempo, a number of quarter notes per minute between `MIN_BPM` and `MAX_BPM`
+fn expect_bpm(token : &Token, pointer : &mut usize) -> Result<f32, Diagnostic> {
+    let bpm = expect_number(token, pointer)? as f32;
+    if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+        return Err(Diagnostic::error(format!("BPM must be between {} and {} !", MIN_BPM, MAX_BPM), token.span)
+            .with_hint(&format!("Found {}", bpm)));
     }
+
+    Ok(bpm)
 }
 
-fn expect_string(token : &T, pointer : &mut usize) -> Result<String, String> {
-    if let T::String(val) = token {
+fn expect_string(token : &Token, pointer : &mut usize) -> Result<String, Diagnostic> {
+    if let T::String(val) = &token.kind {
         *pointer +=1;
         Ok(val.clone())
     }
     else {
-        Err(format!("Expected string, found {:?}", token))
+        Err(Diagnostic::error(format!("Expected string, found {:?}", token.kind), token.span))
     }
 }
 
-fn to_u8<A>(x : A) -> Result<u8, String> where A: TryInto<u8>, A: Debug, A: Copy {
+fn to_i32(x : isize, token : &Token) -> Result<i32, Diagnostic> {
+    x.try_into().map_err(|_| Diagnostic::error(format!("Pitch {} is out of range.", x), token.span))
+}
+
+fn to_u8<A>(x : A, token : &Token) -> Result<u8, Diagnostic> where A: TryInto<u8>, A: Debug, A: Copy {
     match x.try_into() {
         Ok(a) => Ok(a),
-        Err(_) => Err(format!("Cannot convert {:?} to an 8-bit integer.",x)),
+        Err(_) => Err(Diagnostic::error(format!("Cannot convert {:?} to an 8-bit integer.",x), token.span)),
     }
 }
 
@@ -225,8 +1257,9 @@ fn parse_plain() {
     let plain_code = std::fs::read_to_string("./tests/codebase/plain.xfzd")
         .expect("Impossible de lire le fichier");
 
-    let axiom = parse(plain_code).unwrap();
-    dbg!(axiom);
+    let (axiom, diagnostics) = parse(plain_code);
+    assert!(diagnostics.is_empty());
+    dbg!(axiom.unwrap());
 }
 
 #[test]
@@ -234,9 +1267,450 @@ fn parse_simple() {
     let code = std::fs::read_to_string("./tests/codebase/simple_bloc.xfzd")
         .expect("Impossible de lire le fichier");
 
-    let axiom = parse(code).unwrap();
-    dbg!(axiom);
+    let (axiom, diagnostics) = parse(code);
+    assert!(diagnostics.is_empty());
+    dbg!(axiom.unwrap());
 }
 
 
 
+
+
+#[test]
+fn parse_comments() {
+    let code = std::fs::read_to_string("./tests/codebase/comments.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let (axiom, diagnostics) = parse(code);
+    assert!(diagnostics.is_empty());
+    match &axiom.unwrap().blocks[0] {
+        Block::Recursive(block) => assert_eq!(block.blocks.len(), 2),
+        _ => panic!("Expected a block"),
+    }
+}
+
+#[test]
+fn parse_error_has_location() {
+    let code = "bpm 90\n3/4\nsimple(1, 2 3)\n".to_string();
+
+    let diagnostic = &parse(code).1[0];
+    assert_eq!(diagnostic.message, "Expected `,`, found Num(3)");
+    assert_eq!((diagnostic.span.line, diagnostic.span.column), (3, 13));
+}
+
+#[test]
+fn truncated_code_does_not_panic() {
+    let code = "bpm 90\n3/4\n<echo:100>(\n    simple(1, 2, 3)\n";
+    for end in 0..code.len() {
+        let _ = parse(code[..end].to_string());
+    }
+    let (_, diagnostics) = parse(code.to_string());
+    assert_eq!(diagnostics[0].message, "Expected `)`, found end of file");
+}
+
+#[test]
+fn parse_recovers_after_errors() {
+    let code = "bpm 90\n3/4\nsimple(1, 2 3)\n(\n    simple(1, 2, 3)\n    simple(4 ; 5\n)\n)\nsimple(4, 5, 6)\n";
+
+    let (axiom, diagnostics) = parse(code.to_string());
+    let axiom = axiom.unwrap();
+    let lines : Vec<usize> = diagnostics.iter().map(|d| d.span.line).collect();
+    assert_eq!(lines, vec![6, 3, 6, 8]);
+    assert_eq!(axiom.blocks.len(), 2);
+    match &axiom.blocks[0] {
+        Block::Recursive(block) => assert_eq!(block.blocks.len(), 1),
+        _ => panic!("Expected a block"),
+    }
+    assert!(matches!(axiom.blocks[1], Block::Instrument(_)));
+}
+
+#[test]
+fn no_axiom_without_header() {
+    let (axiom, diagnostics) = parse("bpm\nsimple(1, 2, 3)\n".to_string());
+
+    assert!(axiom.is_none());
+    assert_eq!(diagnostics.len(), 1);
+}
+
+#[test]
+fn parse_note_names() {
+    let (axiom, diagnostics) = parse("bpm 90\n3/4\nsimple(C4, 61, Eb4)\n".to_string());
+
+    assert!(diagnostics.is_empty());
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.notes, vec![
+            Note::new(NoteKind::Pitch(Pitch(60))),
+            Note::new(NoteKind::Pitch(Pitch(61))),
+            Note::new(NoteKind::Pitch(Pitch(63))),
+        ]),
+        _ => panic!("Expected an instrument"),
+    }
+}
+
+#[test]
+fn parse_reference_directive() {
+    let (axiom, diagnostics) = parse("bpm 90\n3/4\nreference 432\nsimple(1, 2, 3)\n".to_string());
+    assert!(diagnostics.is_empty());
+    let axiom = axiom.unwrap();
+    assert_eq!(axiom.reference, Some(432.));
+    assert_eq!(axiom.blocks.len(), 1);
+
+    let (axiom, diagnostics) = parse("bpm 90\n3/4\nreference 0\nsimple(1, 2, 3)\n".to_string());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(axiom.unwrap().reference, None);
+}
+
+#[test]
+fn parse_tuning_directive() {
+    let (axiom, diagnostics) = parse("bpm 90\n3/4\ntuning 31edo\nreference 432\nsimple(1, 2, 3)\n".to_string());
+
+    assert!(diagnostics.is_empty());
+    let axiom = axiom.unwrap();
+    let tuning = axiom.tuning.unwrap();
+    assert_eq!(tuning.name, "31edo");
+    assert_eq!((tuning.span.start, tuning.span.end), (18, 23));
+    assert_eq!(axiom.reference, Some(432.));
+}
+
+#[test]
+fn parse_groups() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nsimple(1, [2 3], [4, [5 6]@2])\n".to_string());
+    assert!(diagnostics.is_empty());
+
+    let pitch = |key| Note::new(NoteKind::Pitch(Pitch(key)));
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.notes, vec![
+            pitch(1),
+            Note::new(NoteKind::Group(vec![pitch(2), pitch(3)])),
+            Note::new(NoteKind::Group(vec![
+                pitch(4),
+                Note {kind : NoteKind::Group(vec![pitch(5), pitch(6)]), weight : 2},
+            ])),
+        ]),
+        _ => panic!("Expected an instrument"),
+    }
+
+    let (_, diagnostics) = parse("bpm 90\n4/4\nsimple(1@0, [2 3)\n".to_string());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Invalid note weight : 0");
+}
+
+#[test]
+fn parse_chords() {
+    let (axiom, diagnostics) = parse("bpm 90\n3/4\nsimple({60 64 67}, {C4, Eb4}, D4m7)\n".to_string());
+    assert!(diagnostics.is_empty());
+
+    let chord = |keys : &[i32]| Note::new(NoteKind::Chord(keys.iter().map(|&key| Pitch(key)).collect()));
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.notes, vec![
+            chord(&[60, 64, 67]), chord(&[60, 63]), chord(&[62, 65, 69, 72]),
+        ]),
+        _ => panic!("Expected an instrument"),
+    }
+
+    let (_, diagnostics) = parse("bpm 90\n3/4\nsimple({}, C4foo, 1)\nsimple({1 2, 3)\n".to_string());
+    let messages : Vec<&str> = diagnostics.iter().map(|d| &d.message[..]).collect();
+    assert_eq!(messages, vec!["Empty chord", "Expected pitch, found `)`"]);
+}
+
+#[test]
+fn parse_key_directive() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nkey F# dorian\nsimple(^1, ^10, ii7, V)\n".to_string());
+    assert!(diagnostics.is_empty());
+    let axiom = axiom.unwrap();
+    assert_eq!(axiom.key, Some(Key {tonic : 6, scale : scale_intervals("dorian").unwrap()}));
+
+    let chord = |symbol| Note::new(NoteKind::ChordDegree(parse_numeral(symbol).unwrap()));
+    match &axiom.blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.notes, vec![
+            Note::new(NoteKind::Degree(1)), Note::new(NoteKind::Degree(10)), chord("ii7"), chord("V"),
+        ]),
+        _ => panic!("Expected an instrument"),
+    }
+
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nkey H minor\nkey D locrien\nsimple(^0, IIII, 1, 2)\n".to_string());
+    let messages : Vec<&str> = diagnostics.iter().map(|d| &d.message[..]).collect();
+    assert_eq!(messages, vec!["Invalid tonic : H", "Unknown scale : locrien", "Invalid scale degree : 0"]);
+    assert_eq!(axiom.unwrap().key, None);
+}
+
+#[test]
+fn parse_signatures() {
+    let signature = |code : &str| parse(format!("bpm 90\n{}\n", code)).0.map(|axiom| axiom.signature);
+
+    assert_eq!(signature("3/4"), Some(Signature {groups : vec![3], denominator : 4}));
+    assert_eq!(signature("3+2+2/8"), Some(Signature {groups : vec![3, 2, 2], denominator : 8}));
+    assert_eq!(signature("7/6"), None);
+    assert_eq!(signature("0/4"), None);
+    assert_eq!(signature("3+/4"), None);
+}
+
+#[test]
+fn signature_pulses() {
+    let pulses = |groups : &[u8], denominator| Signature {groups : groups.to_vec(), denominator}.pulses();
+
+    assert_eq!(pulses(&[4], 4), vec![1.; 4]);
+    assert_eq!(pulses(&[7], 8), vec![0.5; 7]);
+    assert_eq!(pulses(&[6], 8), vec![1.5; 2]);
+    assert_eq!(pulses(&[12], 8), vec![1.5; 4]);
+    assert_eq!(pulses(&[3], 8), vec![0.5; 3]);
+    assert_eq!(pulses(&[2], 2), vec![2.; 2]);
+    assert_eq!(pulses(&[3, 2, 2], 8), vec![1.5, 1., 1.]);
+    assert_eq!(Signature {groups : vec![3, 2, 2], denominator : 8}.length(), 3.5);
+}
+
+#[test]
+fn parse_tempo() {
+    let tempo = |code : &str| parse(format!("bpm {}\n4/4\n", code)).0.map(|axiom| axiom.tempo);
+
+    assert_eq!(tempo("128.5"), Some(Tempo {start : 128.5, end : 128.5}));
+    assert_eq!(tempo("300"), Some(Tempo {start : 300., end : 300.}));
+    assert_eq!(tempo("120 -> 140"), Some(Tempo {start : 120., end : 140.}));
+    assert_eq!(tempo("0"), None);
+    assert_eq!(tempo("1000"), Some(Tempo {start : 1000., end : 1000.}));
+    assert_eq!(tempo("1e9"), None);
+    assert_eq!(tempo("120 -> 0.0001"), None);
+
+    let diagnostics = parse("bpm 90 -> 5000\n4/4\n".to_string()).1;
+    assert_eq!(diagnostics[0].message, "BPM must be between 1 and 1000 !");
+    assert_eq!((diagnostics[0].span.line, diagnostics[0].span.column), (1, 11));
+    assert_eq!(tempo("120 ->"), None);
+}
+
+#[test]
+fn tempo_ramps() {
+    let steady = Tempo {start : 120., end : 120.};
+    assert_eq!(steady.seconds(4., 4.), 2.);
+
+    // From 60 to 120 bpm, the bar is shorter than at 60 and longer than at 120
+    let ramp = Tempo {start : 60., end : 120.};
+    let duration = ramp.seconds(4., 4.);
+    assert!((duration - 4. * 2f32.ln()).abs() < 1e-5);
+    assert!(ramp.seconds(1., 4.) > duration / 4.);
+}
+
+#[test]
+fn parse_filter_values() {
+    let (axiom, diagnostics) = parse("bpm 90\n3/4\nreference 432.5hz\nsimple<lp:800hz q=70%, echo:time=250ms 0.5 -1, rev, eq:[1 -2, \"x\"] shape=saw>(1, 2, 3)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+    assert_eq!(axiom.reference, Some(432.5));
+
+    let arg = |name : Option<&str>, value| FilterArg {name : name.map(str::to_string), value};
+    let number = |value, unit| FilterValue::Number(value, unit);
+    match &axiom.blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.filters.iter().map(|filter| (&filter.name[..], &filter.args[..])).collect::<Vec<_>>(), vec![
+            ("lp", &[
+                arg(None, number(800., Some(Unit::Hertz))), arg(Some("q"), number(70., Some(Unit::Percent))),
+            ][..]),
+            ("echo", &[
+                arg(Some("time"), number(250., Some(Unit::Milliseconds))), arg(None, number(0.5, None)), arg(None, number(-1., None)),
+            ]),
+            ("rev", &[]),
+            ("eq", &[
+                arg(None, FilterValue::List(vec![number(1., None), number(-2., None), FilterValue::Text("x".to_string())])),
+                arg(Some("shape"), FilterValue::Ident("saw".to_string())),
+            ]),
+        ]),
+        _ => panic!("Expected an instrument"),
+    }
+
+    let (_, diagnostics) = parse("bpm 90hz\n3/4\n".to_string());
+    assert_eq!(diagnostics[0].message, "Unexpected unit : hz");
+    let (_, diagnostics) = parse("bpm 90\n3/4\nsimple<lp:>(1, 2, 3)\n".to_string());
+    assert_eq!(diagnostics[0].message, "Expected filter argument, found `>`");
+}
+
+#[test]
+fn parse_functions() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\ndef arp(root, step) = simple(root, root+4, root+step*2-1, (C4-root)/2)\nsimple(arp(60, 3), [60 -1], 60-1)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+
+    let name = |name : &str, start| Expr::Name(Reference {name : name.to_string(), span : Span {start, end : start + name.len(), line : 3, column : start - 10}});
+    let binary = |left, operator, right, start| Expr::Binary(Box::new(left), operator, Box::new(right), Span {start, end : start + 1, line : 3, column : start - 10});
+    match &axiom.blocks[0] {
+        Block::Def(def) => {
+            assert_eq!((&def.name[..], &def.params[..]), ("arp", &["root".to_string(), "step".to_string()][..]));
+            match &def.body {
+                LetValue::Block(block) => match &**block {
+                    Block::Instrument(instrument) => assert_eq!(instrument.notes.iter().map(|note| note.kind.clone()).collect::<Vec<_>>(), vec![
+                        NoteKind::Reference(Reference {name : "root".to_string(), span : Span {start : 40, end : 44, line : 3, column : 30}}),
+                        NoteKind::Expr(binary(name("root", 46), Operator::Add, Expr::Value(4), 50)),
+                        NoteKind::Expr(binary(
+                            binary(name("root", 54), Operator::Add, binary(name("step", 59), Operator::Mul, Expr::Value(2), 63), 58),
+                            Operator::Sub, Expr::Value(1), 65,
+                        )),
+                        NoteKind::Expr(binary(binary(Expr::Value(60), Operator::Sub, name("root", 73), 72), Operator::Div, Expr::Value(2), 78)),
+                    ]),
+                    _ => panic!("Expected an instrument"),
+                },
+                _ => panic!("Expected a block"),
+            }
+        },
+        _ => panic!("Expected a definition"),
+    }
+    match &axiom.blocks[1] {
+        Block::Instrument(instrument) => {
+            let kinds : Vec<&NoteKind> = instrument.notes.iter().map(|note| &note.kind).collect();
+            assert!(matches!(kinds[0], NoteKind::Call(call) if call.name == "arp" && call.args.len() == 2));
+            assert_eq!(kinds[1], &NoteKind::Group(vec![
+                Note::new(NoteKind::Pitch(Pitch(60))), Note::new(NoteKind::Hold), Note::new(NoteKind::Pitch(Pitch(1))),
+            ]));
+            assert!(matches!(kinds[2], NoteKind::Expr(_)));
+        },
+        _ => panic!("Expected an instrument"),
+    }
+
+    let (_, diagnostics) = parse("bpm 90\n4/4\ndef wet(x) = <echo:100>\ndef f(x y) = simple(x)\n".to_string());
+    let messages : Vec<&str> = diagnostics.iter().map(|d| &d.message[..]).collect();
+    assert_eq!(messages, vec!["Functions return a note list or a block", "Expected `,`, found Str(\"y\")"]);
+}
+
+#[test]
+fn parse_sections() {
+    let code = std::fs::read_to_string("./tests/codebase/song.xfzd").expect("Impossible de lire le fichier");
+    let (axiom, diagnostics) = parse(code);
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+
+    let song : Vec<&str> = axiom.song.iter().flatten().map(|reference| &reference.name[..]).collect();
+    assert_eq!(song, vec!["intro", "verse", "verse", "chorus", "verse"]);
+    let sections : Vec<(&str, usize)> = axiom.blocks.iter().filter_map(|block| match block {
+        Block::Section(section) => Some((&section.name[..], section.blocks.len())),
+        _ => None,
+    }).collect();
+    assert_eq!(sections, vec![("intro", 1), ("verse", 2), ("chorus", 1)]);
+
+    let messages = |code : &str| {
+        let (_, diagnostics) = parse(format!("bpm 90\n4/4\n{}\n", code));
+        diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect::<Vec<_>>()
+    };
+    assert_eq!(messages("song"), vec!["The song has no section"]);
+    assert_eq!(messages("song A*0 B"), vec!["A section is played at least once"]);
+    assert_eq!(messages("simple(1, 2, 3, 4)\nsong A"), vec!["Unexpected `song`"]);
+    assert_eq!(messages("section A simple(1, 2, 3, 4)"), vec!["Expected `(`, found Str(\"simple\")"]);
+}
+
+#[test]
+fn parse_alternations_and_conditions() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nsimple<when:bar%8==7, when:bar!=0 rev>(1, (1 | 5 | 7), ([2 3] | _)@2)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+    let pitch = |key| Note::new(NoteKind::Pitch(Pitch(key)));
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => {
+            assert_eq!(instrument.notes, vec![
+                pitch(1),
+                Note::new(NoteKind::Alternation(vec![pitch(1), pitch(5), pitch(7)])),
+                Note {kind : NoteKind::Alternation(vec![Note::new(NoteKind::Group(vec![pitch(2), pitch(3)])), Note::new(NoteKind::Rest)]), weight : 2},
+            ]);
+            let values : Vec<Vec<&FilterValue>> = instrument.filters.iter()
+                .map(|filter| filter.args.iter().map(|arg| &arg.value).collect())
+                .collect();
+            assert_eq!(values, vec![
+                vec![&FilterValue::Condition(Condition {period : Some(8), negated : false, value : 7})],
+                vec![&FilterValue::Condition(Condition {period : None, negated : true, value : 0}), &FilterValue::Ident("rev".to_string())],
+            ]);
+        },
+        _ => panic!("Expected an instrument"),
+    }
+
+    let condition = Condition {period : Some(4), negated : false, value : 3};
+    assert_eq!((0..8).filter(|&bar| condition.holds(bar)).collect::<Vec<_>>(), vec![3, 7]);
+
+    let messages = |code : &str| {
+        let (_, diagnostics) = parse(format!("bpm 90\n4/4\n{}\n", code));
+        diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect::<Vec<_>>()
+    };
+    assert_eq!(messages("simple((1@2 | 3), 2, 3, 4)"), vec!["Alternatives have no weight"]);
+    assert_eq!(messages("simple((1 | 3, 2, 3, 4)"), vec!["Expected `)`, found `,`"]);
+    assert_eq!(messages("simple<when:bar%0==1>(1, 2, 3, 4)"), vec!["The period must be positive"]);
+    assert_eq!(messages("simple<when:bar%8>(1, 2, 3, 4)"), vec!["Expected `==` or `!=`, found `>`"]);
+}
+
+#[test]
+fn parse_random_notes() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nsimple(C4?, 62?30%@2, [60? 61], rand(C4, C5))\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+    let pitch = |key| Note::new(NoteKind::Pitch(Pitch(key)));
+    let maybe = |key, probability| NoteKind::Maybe(Box::new(pitch(key)), probability);
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(instrument.notes, vec![
+            Note::new(maybe(60, 0.5)),
+            Note {kind : maybe(62, 0.3), weight : 2},
+            Note::new(NoteKind::Group(vec![Note::new(maybe(60, 0.5)), pitch(61)])),
+            Note::new(NoteKind::Random(Expr::Value(60), Expr::Value(72))),
+        ]),
+        _ => panic!("Expected an instrument"),
+    }
+
+    let messages = |code : &str| {
+        let (_, diagnostics) = parse(format!("bpm 90\n4/4\n{}\n", code));
+        diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect::<Vec<_>>()
+    };
+    assert_eq!(messages("simple(C4?2, 1, 2, 3)"), vec!["Probabilities are between 0 and 1"]);
+    assert_eq!(messages("simple(C4?30ms, 1, 2, 3)"), vec!["A probability cannot be in ms"]);
+    assert_eq!(messages("simple(rand(1), 1, 2, 3)"), vec!["`rand` takes 2 arguments, found 1"]);
+    assert_eq!(messages("def rand(x) = (x)"), vec!["`rand` is built in and cannot be redefined"]);
+}
+
+#[test]
+fn parse_euclidean_rhythms() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nsimple(C2:euclid(3, 8)@2, {C4 E4}:euclid(2, 5, -1)?, 60)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+
+    let notes = match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => instrument.notes.clone(),
+        _ => panic!("Expected an instrument"),
+    };
+    match &notes[0] {
+        Note {kind : NoteKind::Euclid(euclid), weight : 2} => {
+            assert_eq!(*euclid.note, Note::new(NoteKind::Pitch(Pitch(36))));
+            assert_eq!((&euclid.hits, &euclid.steps, &euclid.rotation), (&Expr::Value(3), &Expr::Value(8), &Expr::Value(0)));
+        },
+        note => panic!("Expected a euclidean rhythm, found {:?}", note),
+    }
+    match &notes[1].kind {
+        NoteKind::Maybe(maybe, _) => match &maybe.kind {
+            NoteKind::Euclid(euclid) => {
+                assert_eq!(euclid.note.kind, NoteKind::Chord(vec![Pitch(60), Pitch(64)]));
+                assert_eq!(euclid.rotation, Expr::Value(-1));
+            },
+            kind => panic!("Expected a euclidean rhythm, found {:?}", kind),
+        },
+        kind => panic!("Expected a note with a probability, found {:?}", kind),
+    }
+
+    let messages = |code : &str| {
+        let (_, diagnostics) = parse(format!("bpm 90\n4/4\n{}\n", code));
+        diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect::<Vec<_>>()
+    };
+    assert_eq!(messages("simple(C2:euclid(3)@4)"), vec!["`euclid` takes 2 or 3 arguments, found 1"]);
+    assert_eq!(messages("simple(C2:euclid(3, 8, 1, 2)@4)"), vec!["`euclid` takes 2 or 3 arguments, found 4"]);
+    assert_eq!(messages("simple(C2:clave(3, 8)@4)"), vec!["Unknown rhythm : clave"]);
+}
+
+#[test]
+fn parse_swing_directive() {
+    let swing = |header : &str| {
+        let (axiom, diagnostics) = parse(format!("bpm 90\n4/4\n{}\nsimple(1, 2, 3, 4)\n", header));
+        let axiom = axiom.unwrap();
+        assert_eq!(axiom.blocks.len(), 1);
+        (axiom.swing, diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect::<Vec<_>>())
+    };
+
+    assert_eq!(swing("swing 60%"), (Groove::swing(0.6), vec![]));
+    assert_eq!(swing("swing mpc16"), (groove_template("mpc16"), vec![]));
+    assert_eq!(swing("swing laid_back"), (groove_template("laid_back"), vec![]));
+    assert_eq!(swing("swing 90%"), (None, vec!["Swing is between 50% and 75%".to_string()]));
+    assert_eq!(swing("swing 60"), (None, vec!["Swing is written in percent".to_string()]));
+    assert_eq!(swing("swing funk"), (None, vec!["Unknown groove : funk".to_string()]));
+
+    // An instrument named swing is not the directive
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nswing(1, 2, 3, 4)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+    assert_eq!((axiom.swing, axiom.blocks.len()), (None, 1));
+}
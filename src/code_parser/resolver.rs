@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+
+use super::diagnostic::{Diagnostic, Span};
+use super::parser::*;
+
+/// Function defined with `def`, its body resolved except for its parameters
+#[derive(Debug)]
+pub struct Function {
+    pub params : Vec<String>,
+    pub body : LetValue,
+}
+
+/// What a name stands for
+enum Binding {
+    /// Value of a `let`, resolved when bound so that it contains no name
+    Value(LetValue),
+    /// Function taking this number of arguments
+    Function(usize),
+    /// Parameter of the enclosing function, replaced by the evaluator
+    Param,
+}
+
+/// Names bound in scope, the innermost block last, and all the functions.
+/// Functions are looked up by name by the evaluator, so their names are
+/// unique in the whole code.
+struct Scopes {
+    scopes : Vec<HashMap<String, Binding>>,
+    functions : HashMap<String, Function>,
+}
+
+impl Scopes {
+    fn get(&self, name : &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn is_defined(&self, name : &str) -> bool {
+        self.get(name).is_some() || self.functions.contains_key(name)
+    }
+
+    fn insert(&mut self, name : String, binding : Binding) {
+        self.scopes.last_mut().unwrap().insert(name, binding);
+    }
+}
+
+/// Replaces the names bound with `let` by their values and removes the
+/// `let`s and `def`s, the functions being returned for the evaluator. A
+/// binding is visible from the next line to the end of its block, a function
+/// in its own body too. Names cannot be bound twice, even in a nested block.
+/// Blocks using a name wrongly are reported and left out.
+pub fn resolve(axiom : &mut Axiom, diagnostics : &mut Vec<Diagnostic>) -> HashMap<String, Function> {
+    let mut scopes = Scopes { scopes : Vec::new(), functions : HashMap::new() };
+    let blocks = std::mem::take(&mut axiom.blocks);
+    axiom.blocks = resolve_blocks(blocks, &mut scopes, diagnostics);
+    check_song(axiom, diagnostics);
+
+    scopes.functions
+}
+
+/// Leaves out the sections defined twice, and the names of the song that are
+/// not sections.
+fn check_song(axiom : &mut Axiom, diagnostics : &mut Vec<Diagnostic>) {
+    let mut names = HashSet::new();
+    axiom.blocks.retain(|block| match block {
+        Block::Section(section) if !names.insert(section.name.clone()) => {
+            diagnostics.push(Diagnostic::error(format!("Section `{}` is already defined", section.name), section.span));
+            false
+        },
+        _ => true,
+    });
+
+    if let Some(song) = axiom.song.as_mut() {
+        let mut reported = HashSet::new();
+        song.retain(|reference| {
+            if !names.contains(&reference.name) && reported.insert(reference.name.clone()) {
+                diagnostics.push(Diagnostic::error(format!("Undefined section : {}", reference.name), reference.span)
+                    .with_hint("Sections are written `section name (`, their blocks on the next lines, then `)`"));
+            }
+            names.contains(&reference.name)
+        });
+        if song.is_empty() {
+            axiom.song = None;
+        }
+    }
+}
+
+fn resolve_blocks(blocks : Vec<Block>, scopes : &mut Scopes, diagnostics : &mut Vec<Diagnostic>) -> Vec<Block> {
+    scopes.scopes.push(HashMap::new());
+    let mut resolved = Vec::new();
+    for block in blocks {
+        let result = match block {
+            Block::Let(binding) => bind(binding, scopes, diagnostics),
+            Block::Def(def) => define(def, scopes, diagnostics),
+            // Only the top-level scope is open
+            Block::Section(section) if scopes.scopes.len() == 1 => {
+                resolved.push(Block::Section(Section {
+                    blocks : resolve_blocks(section.blocks, scopes, diagnostics),
+                    ..section
+                }));
+                Ok(())
+            },
+            block => resolve_block(block, scopes, diagnostics).map(|block| resolved.push(block)),
+        };
+        if let Err(diagnostic) = result {
+            diagnostics.push(diagnostic);
+        }
+    }
+    scopes.scopes.pop();
+
+    resolved
+}
+
+fn already_defined(name : &str, span : Span) -> Diagnostic {
+    Diagnostic::error(format!("`{}` is already defined", name), span)
+        .with_hint("Names cannot be redefined, nor shadowed in a nested block")
+}
+
+fn bind(binding : Let, scopes : &mut Scopes, diagnostics : &mut Vec<Diagnostic>) -> Result<(), Diagnostic> {
+    if scopes.is_defined(&binding.name) {
+        return Err(already_defined(&binding.name, binding.span));
+    }
+
+    let value = resolve_value(binding.value, scopes, diagnostics)?;
+    scopes.insert(binding.name, Binding::Value(value));
+
+    Ok(())
+}
+
+fn define(def : Def, scopes : &mut Scopes, diagnostics : &mut Vec<Diagnostic>) -> Result<(), Diagnostic> {
+    if scopes.is_defined(&def.name) {
+        return Err(already_defined(&def.name, def.span));
+    }
+
+    // Bound before its body, so that it can call itself
+    scopes.insert(def.name.clone(), Binding::Function(def.params.len()));
+    scopes.scopes.push(HashMap::new());
+    let mut body = Ok(def.body);
+    for param in &def.params {
+        if scopes.is_defined(param) {
+            body = Err(already_defined(param, def.span));
+            break;
+        }
+        scopes.insert(param.clone(), Binding::Param);
+    }
+    let body = body.and_then(|body| resolve_value(body, scopes, diagnostics));
+    scopes.scopes.pop();
+
+    match body {
+        Ok(body) => {
+            scopes.functions.insert(def.name, Function {params : def.params, body});
+            Ok(())
+        },
+        Err(diagnostic) => {
+            scopes.scopes.last_mut().unwrap().remove(&def.name);
+            Err(diagnostic)
+        },
+    }
+}
+
+fn resolve_value(value : LetValue, scopes : &mut Scopes, diagnostics : &mut Vec<Diagnostic>) -> Result<LetValue, Diagnostic> {
+    Ok(match value {
+        LetValue::Notes(notes) => LetValue::Notes(resolve_notes(notes, scopes)?),
+        LetValue::Filters(filters) => LetValue::Filters(resolve_filters(filters, scopes)?),
+        LetValue::Block(block) => LetValue::Block(Box::new(resolve_block(*block, scopes, diagnostics)?)),
+    })
+}
+
+fn resolve_block(block : Block, scopes : &mut Scopes, diagnostics : &mut Vec<Diagnostic>) -> Result<Block, Diagnostic> {
+    Ok(match block {
+        Block::Recursive(block) => Block::Recursive(RecBlock {
+            filters : resolve_filters(block.filters, scopes)?,
+            blocks : resolve_blocks(block.blocks, scopes, diagnostics),
+        }),
+        // A function returning a block is called like an instrument
+        Block::Instrument(instrument) if matches!(scopes.get(&instrument.instrument), Some(Binding::Function(_))) => {
+            let filters = resolve_filters(instrument.filters, scopes)?;
+            let (name, span) = (instrument.instrument, instrument.span);
+            let args = instrument.notes.into_iter().map(|note| match note.kind {
+                NoteKind::Pitch(pitch) if note.weight == 1 => Ok(Expr::Value(pitch.0)),
+                NoteKind::Reference(reference) if note.weight == 1 => Ok(Expr::Name(reference)),
+                NoteKind::Expr(expr) if note.weight == 1 => Ok(expr),
+                _ => Err(Diagnostic::error(format!("Arguments of `{}` must be pitches", name), span)),
+            }).collect::<Result<Vec<Expr>, Diagnostic>>()?;
+            let call = resolve_call(Call {name, args, span}, scopes)?;
+            if filters.is_empty() {
+                Block::Call(call)
+            }
+            else {
+                Block::Recursive(RecBlock {filters, blocks : vec![Block::Call(call)]})
+            }
+        },
+        Block::Instrument(instrument) => Block::Instrument(Instrument {
+            filters : resolve_filters(instrument.filters, scopes)?,
+            notes : resolve_notes(instrument.notes, scopes)?,
+            ..instrument
+        }),
+        Block::Reference(reference) => match lookup(&reference, scopes)? {
+            Binding::Value(LetValue::Block(block)) => (**block).clone(),
+            binding => return Err(wrong_kind(&reference, binding, "a block")),
+        },
+        Block::Call(call) => Block::Call(resolve_call(call, scopes)?),
+        Block::Let(Let {span, ..}) | Block::Def(Def {span, ..}) => return Err(Diagnostic::error("Unexpected definition".to_string(), span)),
+        Block::Section(section) => return Err(Diagnostic::error("Sections are written at the top level".to_string(), section.span)),
+    })
+}
+
+/// Splices the bound note lists, as a group if the name has a weight.
+fn resolve_notes(notes : Vec<Note>, scopes : &Scopes) -> Result<Vec<Note>, Diagnostic> {
+    let mut resolved = Vec::new();
+    for note in notes {
+        match note.kind {
+            NoteKind::Reference(reference) => match lookup(&reference, scopes)? {
+                Binding::Value(LetValue::Notes(bound)) if note.weight == 1 => resolved.extend(bound.iter().cloned()),
+                Binding::Value(LetValue::Notes(bound)) => resolved.push(Note {kind : NoteKind::Group(bound.clone()), weight : note.weight}),
+                Binding::Param => resolved.push(Note {kind : NoteKind::Expr(Expr::Name(reference)), ..note}),
+                binding => return Err(wrong_kind(&reference, binding, "a note list")),
+            },
+            NoteKind::Group(children) => resolved.push(Note {kind : NoteKind::Group(resolve_notes(children, scopes)?), ..note}),
+            NoteKind::Maybe(maybe, probability) => {
+                let maybe = Note::single(resolve_notes(vec![*maybe], scopes)?);
+                resolved.push(Note {kind : NoteKind::Maybe(Box::new(maybe), probability), ..note});
+            },
+            NoteKind::Random(low, high) => {
+                check_expr(&low, scopes)?;
+                check_expr(&high, scopes)?;
+                resolved.push(Note {kind : NoteKind::Random(low, high), ..note});
+            },
+            NoteKind::Euclid(euclid) => {
+                for expr in [&euclid.hits, &euclid.steps, &euclid.rotation] {
+                    check_expr(expr, scopes)?;
+                }
+                let hit = Note::single(resolve_notes(vec![*euclid.note], scopes)?);
+                resolved.push(Note {kind : NoteKind::Euclid(Euclid {note : Box::new(hit), ..euclid}), ..note});
+            },
+            // Each alternative is played in a single turn, even when a name stands for several notes
+            NoteKind::Alternation(alternatives) => {
+                let alternatives = alternatives.into_iter()
+                    .map(|alternative| resolve_notes(vec![alternative], scopes).map(Note::single))
+                    .collect::<Result<_, _>>()?;
+                resolved.push(Note {kind : NoteKind::Alternation(alternatives), ..note});
+            },
+            NoteKind::Expr(expr) => {
+                check_expr(&expr, scopes)?;
+                resolved.push(Note {kind : NoteKind::Expr(expr), ..note});
+            },
+            NoteKind::Call(call) => resolved.push(Note {kind : NoteKind::Call(resolve_call(call, scopes)?), ..note}),
+            _ => resolved.push(note),
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Checks that a function is called with the right number of arguments.
+fn resolve_call(call : Call, scopes : &Scopes) -> Result<Call, Diagnostic> {
+    let reference = Reference {name : call.name.clone(), span : call.span};
+    match lookup(&reference, scopes)? {
+        Binding::Function(arity) if *arity == call.args.len() => {},
+        Binding::Function(arity) => return Err(Diagnostic::error(
+            format!("`{}` takes {} argument{}, found {}", call.name, arity, if *arity == 1 {""} else {"s"}, call.args.len()),
+            call.span,
+        )),
+        binding => return Err(wrong_kind(&reference, binding, "a function")),
+    }
+    for arg in &call.args {
+        check_expr(arg, scopes)?;
+    }
+
+    Ok(call)
+}
+
+/// Checks that the names in an expression are parameters.
+fn check_expr(expr : &Expr, scopes : &Scopes) -> Result<(), Diagnostic> {
+    match expr {
+        Expr::Value(_) => Ok(()),
+        Expr::Name(reference) => match lookup(reference, scopes)? {
+            Binding::Param => Ok(()),
+            binding => Err(wrong_kind(reference, binding, "a number")),
+        },
+        Expr::Binary(left, _, right, _) => {
+            check_expr(left, scopes)?;
+            check_expr(right, scopes)
+        },
+    }
+}
+
+/// Splices the bound filter chains, other filters being built-in.
+fn resolve_filters(filters : Vec<Filter>, scopes : &Scopes) -> Result<Vec<Filter>, Diagnostic> {
+    let mut resolved = Vec::new();
+    for filter in filters {
+        match scopes.get(&filter.name) {
+            Some(Binding::Value(LetValue::Filters(bound))) if filter.args.is_empty() => resolved.extend(bound.iter().cloned()),
+            Some(Binding::Value(LetValue::Filters(_))) => return Err(Diagnostic::error(
+                format!("`{}` is a filter chain and takes no arguments", filter.name),
+                filter.span,
+            )),
+            Some(binding) => {
+                let reference = Reference {name : filter.name, span : filter.span};
+                return Err(wrong_kind(&reference, binding, "a filter chain"));
+            },
+            None => resolved.push(filter),
+        }
+    }
+
+    Ok(resolved)
+}
+
+fn lookup<'a>(reference : &Reference, scopes : &'a Scopes) -> Result<&'a Binding, Diagnostic> {
+    scopes.get(&reference.name).ok_or_else(|| {
+        let diagnostic = Diagnostic::error(format!("Undefined name : {}", reference.name), reference.span);
+        if reference.name.chars().all(|c| "IViv".contains(c)) {
+            diagnostic.with_hint("Chord symbols are roman numerals from `I` to `VII`, with `7` for a seventh, e.g. `ii7`")
+        }
+        else {
+            diagnostic.with_hint("Names are bound with `let name = value` before being used")
+        }
+    })
+}
+
+fn wrong_kind(reference : &Reference, binding : &Binding, expected : &str) -> Diagnostic {
+    let found = match binding {
+        Binding::Value(LetValue::Notes(_)) => "a note list",
+        Binding::Value(LetValue::Filters(_)) => "a filter chain",
+        Binding::Value(LetValue::Block(_)) => "a block",
+        Binding::Function(_) => "a function",
+        Binding::Param => "a parameter",
+    };
+    Diagnostic::error(format!("`{}` is {}, not {}", reference.name, found, expected), reference.span)
+}
+
+
+
+/* *************TESTS*************** */
+
+
+#[cfg(test)]
+fn resolved(code : &str) -> (Vec<Block>, Vec<String>) {
+    let (axiom, diagnostics) = super::analyse(code.to_string());
+    let messages = diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect();
+    (axiom.unwrap().blocks, messages)
+}
+
+#[test]
+fn resolve_bindings() {
+    let code = std::fs::read_to_string("./tests/codebase/bindings.xfzd")
+        .expect("Impossible de lire le fichier");
+    let (blocks, messages) = resolved(&code);
+    assert!(messages.is_empty(), "{:?}", messages);
+
+    let pitches = |notes : &[Note]| notes.iter().map(|note| match note.kind {
+        NoteKind::Pitch(pitch) => pitch.0,
+        _ => panic!("Expected a pitch"),
+    }).collect::<Vec<i32>>();
+    let filter_names = |filters : &[Filter]| filters.iter().map(|filter| filter.name.clone()).collect::<Vec<String>>();
+
+    assert_eq!(blocks.len(), 3);
+    match &blocks[0] {
+        Block::Instrument(instrument) => assert_eq!(pitches(&instrument.notes), vec![48, 48, 55, 53]),
+        _ => panic!("Expected an instrument"),
+    }
+    match &blocks[1] {
+        Block::Instrument(instrument) => {
+            assert_eq!(filter_names(&instrument.filters), vec!["echo", "lp", "lp"]);
+            assert_eq!(pitches(&instrument.notes[..2]), vec![60, 62]);
+            assert_eq!(instrument.notes[2].kind, NoteKind::Group(instrument.notes[..2].to_vec()));
+        },
+        _ => panic!("Expected an instrument"),
+    }
+    match &blocks[2] {
+        Block::Recursive(block) => {
+            assert_eq!(filter_names(&block.filters), vec!["echo", "lp"]);
+            assert_eq!(block.blocks.len(), 2);
+        },
+        _ => panic!("Expected a block"),
+    }
+}
+
+#[test]
+fn resolve_errors() {
+    let (blocks, messages) = resolved("bpm 90\n4/4\nlet riff = (1, 2)\nlet riff = (3, 4)\nsimple(riff, bass, 5)\nriff\n(\n    let riff = (5, 6)\n    let wet = <echo:100>\n    simple<wet:3>(riff, riff)\n)\nsimple<riff>(1, 2, 3, 4)\nsimple(IIII, 1, 2, 3)\n");
+
+    assert_eq!(messages, vec![
+        "`riff` is already defined",
+        "Undefined name : bass",
+        "`riff` is a note list, not a block",
+        "`riff` is already defined",
+        "`wet` is a filter chain and takes no arguments",
+        "`riff` is a note list, not a filter chain",
+        "Undefined name : IIII",
+    ]);
+    // The block is kept, without what failed in it
+    assert_eq!(blocks.len(), 1);
+}
+
+#[test]
+fn resolve_sections() {
+    let code = "bpm 90\n4/4\nsong A*2 B C C\nsection A (\n    simple(1, 2, 3, 4)\n)\nsection B (\n    section C (\n        simple(1, 2, 3, 4)\n    )\n)\nsection A (\n    simple(5, 6, 7, 8)\n)\n";
+    let (axiom, diagnostics) = super::analyse(code.to_string());
+    let messages : Vec<String> = diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect();
+
+    assert_eq!(messages, vec![
+        "Sections are written at the top level",
+        "Section `A` is already defined",
+        "Undefined section : C",
+    ]);
+    let axiom = axiom.unwrap();
+    let song : Vec<&str> = axiom.song.iter().flatten().map(|reference| &reference.name[..]).collect();
+    assert_eq!(song, vec!["A", "A", "B"]);
+    assert_eq!(axiom.blocks.len(), 2);
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::code_parser::diagnostic::Diagnostic;
+use crate::code_parser::analyse;
+use crate::code_parser::parser::Axiom;
+use crate::synthesis::buffer_builder::{bar_duration, build_bar, build_song, check_bar, resolve_tuning, section_at, song_length, PlaybackState};
+use crate::synthesis::tuning::Tuning;
+use crate::{utils, RenderedBar};
+
+/// Live-coding session, kept alive between bars.
+///
+/// The code is only replaced when it compiles, so a typo never stops the
+/// music: the previous code keeps playing until the new one is valid. The
+/// blocks that cannot be played are skipped, the others still play.
+#[wasm_bindgen]
+pub struct Engine {
+    axiom : Option<Axiom>,
+    /// Tuning of the current code
+    tuning : Tuning,
+    /// Tunings loaded from Scala files, by name
+    tunings : HashMap<String, Tuning>,
+    state : PlaybackState,
+    sample_position : usize,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Engine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Engine {
+        utils::set_panic_hook();
+        Engine {
+            axiom : None,
+            tuning : Tuning::default(),
+            tunings : HashMap::new(),
+            state : PlaybackState::default(),
+            sample_position : 0,
+        }
+    }
+
+    /// Parses `code` and plays what could be parsed from the next bar on.
+    /// If even the header is wrong, the previous code is kept. The blocks
+    /// that cannot be played in the next bar are reported too.
+    pub fn update_code(&mut self, code : &str) -> Vec<Diagnostic> {
+        let (axiom, mut diagnostics) = analyse(code.to_string());
+        if let Some(axiom) = axiom {
+            match resolve_tuning(&axiom, &self.tunings) {
+                Ok(tuning) => {
+                    diagnostics.extend(check_bar(&axiom, &tuning, &self.state));
+                    self.tuning = tuning;
+                    self.axiom = Some(axiom);
+                },
+                Err(message) => {
+                    let span = axiom.tuning.map(|tuning| tuning.span).unwrap_or_default();
+                    diagnostics.push(Diagnostic::error(message, span)
+                        .with_hint("Built-in tunings are `<n>edo`, `just` and `pythagorean`"));
+                },
+            }
+        }
+        diagnostics
+    }
+
+    /// Makes a tuning from Scala files available as `tuning <name>`.
+    pub fn register_tuning(&mut self, name : &str, scl : &str, kbm : Option<String>) -> Result<(), String> {
+        let tuning = Tuning::from_scala(scl, kbm.as_deref())?;
+        self.tunings.insert(name.to_string(), tuning);
+        Ok(())
+    }
+
+    /// Renders the next bar of the current code, without the blocks that
+    /// cannot be played.
+    pub fn render_next_bar(&mut self) -> Result<RenderedBar, String> {
+        let axiom = self.axiom.as_ref().ok_or("No code to play")?;
+        let samples = build_bar(axiom, &self.tuning, &mut self.state, &mut vec![])?;
+        self.sample_position += samples.len();
+
+        Ok(RenderedBar { samples, duration: bar_duration(axiom) })
+    }
+
+    /// Renders every bar of the song of the current code, from silence, e.g.
+    /// to save it. What is played live is left as it is.
+    pub fn render_song(&self) -> Result<RenderedBar, String> {
+        let axiom = self.axiom.as_ref().ok_or("No code to play")?;
+        let samples = build_song(axiom, &self.tuning, self.state.seed)?;
+
+        Ok(RenderedBar { samples, duration: bar_duration(axiom) * song_length(axiom) as f32 })
+    }
+
+    /// Plays bar `bar_index` of the song next, from silence, so that it sounds
+    /// the same whatever was played before.
+    pub fn seek(&mut self, bar_index : u32) {
+        self.state = PlaybackState { bar_index, tail : vec![], seed : self.state.seed };
+    }
+
+    /// Number of bars of the song of the current code, `0` without code.
+    #[wasm_bindgen(getter)]
+    pub fn song_length(&self) -> u32 {
+        self.axiom.as_ref().map_or(0, song_length)
+    }
+
+    /// Name of the section of the next bar, if it has one.
+    #[wasm_bindgen(getter)]
+    pub fn section(&self) -> Option<String> {
+        let axiom = self.axiom.as_ref()?;
+        section_at(axiom, self.state.bar_index).map(|section| section.name.clone())
+    }
+
+    /// Seed of the random notes and sounds: with the same seed, a bar always
+    /// sounds the same.
+    #[wasm_bindgen(getter)]
+    pub fn seed(&self) -> u64 {
+        self.state.seed
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_seed(&mut self, seed : u64) {
+        self.state.seed = seed;
+    }
+
+    /// Whether some code has been accepted and can be played.
+    #[wasm_bindgen(getter)]
+    pub fn has_code(&self) -> bool {
+        self.axiom.is_some()
+    }
+
+    /// Index of the next bar to be rendered.
+    #[wasm_bindgen(getter)]
+    pub fn bar_index(&self) -> u32 {
+        self.state.bar_index
+    }
+
+    /// Number of samples rendered since the engine was created.
+    #[wasm_bindgen(getter)]
+    pub fn sample_position(&self) -> usize {
+        self.sample_position
+    }
+}
+
+
+
+/* *************TESTS*************** */
+
+
+#[test]
+fn engine_keeps_state_across_bars() {
+    let code = std::fs::read_to_string("./tests/codebase/simple_bloc.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let mut engine = Engine::new();
+    assert!(engine.update_code(&code).is_empty());
+    let first = engine.render_next_bar().unwrap().samples();
+    let second = engine.render_next_bar().unwrap().samples();
+
+    assert_eq!(engine.bar_index(), 2);
+    assert_eq!(engine.sample_position(), first.len() + second.len());
+    // The last note of the first bar rings into the second one
+    assert_eq!(first.len(), second.len());
+    assert_ne!(first, second);
+}
+
+#[test]
+fn engine_keeps_previous_code_on_error() {
+    let code = std::fs::read_to_string("./tests/codebase/simple_bloc.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let mut engine = Engine::new();
+    assert!(engine.render_next_bar().is_err());
+    assert!(engine.update_code(&code).is_empty());
+    assert!(!engine.update_code("bpm\n").is_empty());
+    assert!(engine.render_next_bar().is_ok());
+}
+
+#[test]
+fn engine_plays_what_could_be_parsed() {
+    let mut engine = Engine::new();
+    let diagnostics = engine.update_code("bpm 90\n3/4\nsimple(1, 2, 3)\nsimple(4, 5\nsimple(7, 8, 9)\n");
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(engine.axiom.as_ref().unwrap().blocks.len(), 2);
+    let samples = engine.render_next_bar().unwrap().samples();
+    assert!(samples.iter().any(|&s| s != 0.));
+}
+
+#[test]
+fn engine_skips_the_blocks_it_cannot_play() {
+    let mut engine = Engine::new();
+    let diagnostics = engine.update_code("bpm 90\n4/4\nsimple(1, 2, 3, 4)\nsimple(1, 2)\nsimpl(1, 2, 3, 4)\n");
+
+    let problems : Vec<(&str, usize)> = diagnostics.iter()
+        .map(|diagnostic| (&diagnostic.message[..], diagnostic.span.line))
+        .collect();
+    assert_eq!(problems, vec![
+        ("Invalid number of notes. Found 2, expected 4", 4),
+        ("Unknown instrument name : simpl", 5),
+    ]);
+    assert_eq!(diagnostics[0].span.column, 1);
+    assert_eq!(crate::diagnose("bpm 90\n4/4\nsimple(1, 2, 3, 4)\nsimple(1, 2)\n").len(), 1);
+
+    // The valid block still plays
+    let samples = engine.render_next_bar().unwrap().samples();
+    assert!(samples.iter().any(|&s| s != 0.));
+    let mut alone = Engine::new();
+    alone.update_code("bpm 90\n4/4\nsimple(1, 2, 3, 4)\n");
+    assert_eq!(samples, alone.render_next_bar().unwrap().samples());
+}
+
+#[test]
+fn engine_uses_registered_tunings() {
+    let code = "bpm 90\n3/4\ntuning quarter\nsimple(60, 61, 62)\n";
+    let mut engine = Engine::new();
+
+    let diagnostics = engine.update_code(code);
+    assert_eq!(diagnostics[0].message, "Unknown tuning : quarter");
+    assert!(!engine.has_code());
+
+    assert!(engine.register_tuning("quarter", "Quarter tones\n24\n", None).is_err());
+    let scl = (1..=24).map(|i| format!("{}.0\n", i * 50)).collect::<String>();
+    engine.register_tuning("quarter", &format!("Quarter tones\n24\n{}", scl), None).unwrap();
+    assert!(engine.update_code(code).is_empty());
+    assert!(engine.render_next_bar().is_ok());
+}
+
+#[test]
+fn engine_reports_bar_duration() {
+    let mut engine = Engine::new();
+    assert!(engine.update_code("bpm 100 -> 140\n7/8\nsimple(1, 2, 3, 4, 5, 6, 7)\n").is_empty());
+    let bar = engine.render_next_bar().unwrap();
+
+    assert!(bar.duration() > 3.5 * 60. / 140. && bar.duration() < 3.5 * 60. / 100.);
+    assert_eq!(bar.size(), (bar.duration() * crate::SAMPLE_RATE) as usize);
+}
+
+#[test]
+fn engine_plays_the_song() {
+    let code = std::fs::read_to_string("./tests/codebase/song.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let mut engine = Engine::new();
+    assert_eq!(engine.song_length(), 0);
+    assert!(engine.update_code(&code).is_empty());
+    assert_eq!(engine.song_length(), 5);
+    assert_eq!(engine.section().as_deref(), Some("intro"));
+
+    engine.seek(3);
+    assert_eq!(engine.section().as_deref(), Some("chorus"));
+    let chorus = engine.render_next_bar().unwrap().samples();
+    assert_eq!(engine.section().as_deref(), Some("verse"));
+    engine.render_next_bar().unwrap();
+    engine.seek(3);
+    assert_eq!(engine.render_next_bar().unwrap().samples(), chorus);
+    assert_eq!(engine.bar_index(), 4);
+}
+
+#[test]
+fn engine_randomness_follows_the_seed() {
+    let code = "bpm 120\n4/4\nsimple<shuffle>(C4?, rand(60, 72), E4, G4?70%)\nnoise(_, C5, _, C5?)\n";
+    let render = |seed| {
+        let mut engine = Engine::new();
+        engine.set_seed(seed);
+        assert!(engine.update_code(code).is_empty());
+        (0..4).map(|_| engine.render_next_bar().unwrap().samples()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(render(7), render(7));
+    assert_ne!(render(7), render(8));
+
+    let mut engine = Engine::new();
+    engine.set_seed(7);
+    assert_eq!(engine.seed(), 7);
+    assert!(engine.update_code(code).is_empty());
+    engine.seek(2);
+    let third = engine.render_next_bar().unwrap().samples();
+    engine.seek(2);
+    assert_eq!(engine.render_next_bar().unwrap().samples(), third);
+}
+
+#[test]
+fn engine_renders_the_song() {
+    let code = std::fs::read_to_string("./tests/codebase/song.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let mut engine = Engine::new();
+    assert!(engine.render_song().is_err());
+    assert!(engine.update_code(&code).is_empty());
+    let song = engine.render_song().unwrap();
+    assert_eq!(engine.bar_index(), 0);
+    let bar = engine.render_next_bar().unwrap();
+    assert_eq!(song.duration(), 5. * bar.duration());
+    assert!(song.size() > 5 * bar.size());
+    assert_eq!(song.samples()[..bar.size()], bar.samples()[..]);
+
+    let scl = (1..=24).map(|i| format!("{}.0\n", i * 50)).collect::<String>();
+    engine.register_tuning("quarter", &format!("Quarter tones\n24\n{}", scl), None).unwrap();
+    assert!(engine.update_code("bpm 90\n3/4\ntuning quarter\nsimple(60, 61, 62)\n").is_empty());
+    assert!(engine.render_song().is_ok());
+}
+
+#[test]
+fn engine_renders_the_song_with_its_seed() {
+    let code = "bpm 120\n4/4\nsection A (\n\tnoise(C4?, rand(60, 72), E4, G4?70%)\n)\nsection B (\n\tsimple<shuffle>(C4, D4, E4, rand(60, 72))\n)\n";
+    let mut engine = Engine::new();
+    engine.set_seed(42);
+    assert!(engine.update_code(code).is_empty());
+
+    let song = engine.render_song().unwrap().samples();
+    let first = engine.render_next_bar().unwrap().samples();
+    let second = engine.render_next_bar().unwrap().samples();
+    assert_eq!(song[..first.len()], first[..]);
+    assert_eq!(song[first.len()..first.len() + second.len()], second[..]);
+
+    engine.set_seed(43);
+    assert_ne!(engine.render_song().unwrap().samples(), song);
+}
@@ -1,9 +1,16 @@
 mod code_parser;
+mod engine;
 mod synthesis;
+mod theory;
 mod utils;
 
-use synthesis::buffer_builder::build_buffer;
-use wasm_bindgen::prelude;
+use std::collections::HashMap;
+
+use code_parser::diagnostic::to_message;
+pub use code_parser::diagnostic::{Diagnostic, Severity, Span};
+pub use engine::Engine;
+use synthesis::buffer_builder::{bar_duration, build_buffer, check_bar, resolve_tuning, PlaybackState};
+use wasm_bindgen::prelude::*;
 
 const SAMPLE_RATE: f32 = 44_000.;
 
@@ -13,27 +20,74 @@ const SAMPLE_RATE: f32 = 44_000.;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
-#[prelude::wasm_bindgen]
+#[wasm_bindgen]
 extern "C" {
     fn alert(s: &str);
 }
 
-#[prelude::wasm_bindgen]
-pub struct PointerAndSize {
-    pub pointer: *const f32,
-    pub size: usize,
+/// Samples of a rendered bar.
+///
+/// The samples are owned by this object and stay valid, whatever is compiled
+/// afterwards, until `free()` is called on the JS side.
+#[wasm_bindgen]
+pub struct RenderedBar {
+    samples: Vec<f32>,
+    duration: f32,
+}
+
+#[wasm_bindgen]
+impl RenderedBar {
+    /// Number of samples in the bar.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Exact duration of the bar in seconds, the next bar starting after it.
+    /// The number of samples is rounded down from it, except for a whole song
+    /// that ends with what rings past its last bar.
+    #[wasm_bindgen(getter)]
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
+    /// Address of the first sample in the WASM memory, to build a
+    /// `Float32Array` view without copying. The view must not be used after
+    /// `free()`, nor after the WASM memory grows.
+    #[wasm_bindgen(getter)]
+    pub fn pointer(&self) -> *const f32 {
+        self.samples.as_ptr()
+    }
+
+    /// Copy of the samples (a `Float32Array` on the JS side).
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.clone()
+    }
 }
 
-#[prelude::wasm_bindgen]
-pub fn compile(code: &str) -> Result<PointerAndSize, String> {
+#[wasm_bindgen]
+pub fn compile(code: &str) -> Result<RenderedBar, String> {
     utils::set_panic_hook();
-    let parsed_code = code_parser::parser::parse(code.to_string())?;
-    let audio_buffer = build_buffer(parsed_code)?;
+    let (parsed_code, diagnostics) = code_parser::analyse(code.to_string());
+    let parsed_code = parsed_code.ok_or_else(|| to_message(&diagnostics))?;
+    let samples = build_buffer(&parsed_code)?;
 
-    Ok(PointerAndSize {
-        pointer: audio_buffer.as_ptr(),
-        size: audio_buffer.len(),
-    })
+    Ok(RenderedBar { samples, duration: bar_duration(&parsed_code) })
+}
+
+/// Problems found in `code`, for the editor to underline, including the
+/// blocks that cannot be played in the first bar.
+#[wasm_bindgen]
+pub fn diagnose(code: &str) -> Vec<Diagnostic> {
+    let (parsed_code, mut diagnostics) = code_parser::analyse(code.to_string());
+    if let Some(parsed_code) = parsed_code {
+        // Tunings registered in an engine are not known here
+        if let Ok(tuning) = resolve_tuning(&parsed_code, &HashMap::new()) {
+            diagnostics.extend(check_bar(&parsed_code, &tuning, &PlaybackState::default()));
+        }
+    }
+
+    diagnostics
 }
 
 
@@ -41,8 +95,11 @@ pub fn compile(code: &str) -> Result<PointerAndSize, String> {
 
 #[allow(dead_code)]
 fn compile_test(code : &str) -> Result<Vec<f32>, String> {
-    let parsed_code = code_parser::parser::parse(code.to_string())?;
-    let audio_buffer = build_buffer(parsed_code)?;
+    let (parsed_code, diagnostics) = code_parser::analyse(code.to_string());
+    if !diagnostics.is_empty() {
+        return Err(to_message(&diagnostics));
+    }
+    let audio_buffer = build_buffer(&parsed_code.unwrap())?;
     
     Ok(audio_buffer)
 }
@@ -63,4 +120,32 @@ fn compile_simple() {
         .expect("Impossible de lire le fichier");
 
     compile_test(&code).unwrap();
-}
\ No newline at end of file
+}
+
+#[test]
+fn compile_bindings() {
+    let code = std::fs::read_to_string("./tests/codebase/bindings.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    compile_test(&code).unwrap();
+}
+
+#[test]
+fn rendered_bar_outlives_next_compile() {
+    let simple = std::fs::read_to_string("./tests/codebase/simple_bloc.xfzd")
+        .expect("Impossible de lire le fichier");
+    let plain = std::fs::read_to_string("./tests/codebase/plain.xfzd")
+        .expect("Impossible de lire le fichier");
+
+    let first = compile(&simple).unwrap();
+    let copy = first.samples();
+    assert!(copy.iter().any(|&s| s != 0.));
+
+    let second = compile(&plain).unwrap();
+    let _third = compile(&simple).unwrap();
+
+    assert_eq!(first.samples(), copy);
+    let view = unsafe { std::slice::from_raw_parts(first.pointer(), first.size()) };
+    assert_eq!(view, &copy[..]);
+    assert_ne!(first.pointer(), second.pointer());
+}
@@ -1,106 +1,654 @@
-use std::convert::TryInto;
 use std::cmp::min;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use fastrand::Rng;
 
+use crate::code_parser::diagnostic::Diagnostic;
 use crate::code_parser::parser::*;
+use crate::theory::{groove_template, ChordDegree, Groove, Key};
 
-use super::{AudioBuffer, oscillator::Oscillator, filters::FilterTrait};
+use super::{AudioBuffer, oscillator::Oscillator, filters::FilterTrait, tuning::Tuning};
+
+/// Part of the notes' length during which they are actually held, so that
+/// successive notes can be told apart.
+const GATE : f32 = 0.75;
 
 struct BarContext {
     buffer : AudioBuffer,
-    beat_count : u8,
-    beat_duration : u8,
-    spb : f32,
+    /// Samples ringing past the end of the bar
+    tail : AudioBuffer,
+    /// Lengths of the pulses of the bar, in quarter notes
+    pulses : Vec<f32>,
+    tempo : Tempo,
+    /// Length of the bar, in quarter notes
+    length : f32,
+    tuning : Tuning,
+    key : Option<Key>,
+    /// Groove of the blocks that have none, from the `swing` directive
+    swing : Option<Groove>,
+    bar_index : u32,
+    /// Random generator of the bar, see `bar_rng`
+    rng : Rng,
+    /// Why the blocks that were skipped could not be played
+    errors : Vec<Diagnostic>,
+}
+
+impl BarContext {
+    /// Time in seconds from the beginning of the bar to `position`, in
+    /// quarter notes.
+    fn seconds(&self, position : f32) -> f32 {
+        self.tempo.seconds(position, self.length)
+    }
+}
+
+/// What survives from one bar to the next.
+#[derive(Debug, Default)]
+pub struct PlaybackState {
+    /// Index of the next bar to render
+    pub bar_index : u32,
+    /// Samples of the notes that did not end within the previous bar
+    pub tail : AudioBuffer,
+    /// Seed of the random notes and sounds
+    pub seed : u64,
 }
 
-pub fn build_buffer(tree : Axiom) -> Result<AudioBuffer, String> {
-    // seconds per beat
-    let spb = 60. / (tree.bpm as f32);
-    let (beat_count, beat_duration) = tree.signature;
-    let buffer : Vec<f32> = vec![0.;(crate::SAMPLE_RATE * spb) as usize * beat_count as usize];
+/// Random generator of bar `bar_index`, so that a bar sounds the same for a
+/// given seed, whatever was played before it.
+fn bar_rng(seed : u64, bar_index : u32) -> Rng {
+    Rng::with_seed(seed ^ (bar_index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+/// Renders a single bar, from silence, with the built-in tunings only.
+/// Fails if any block cannot be played.
+pub fn build_buffer(tree : &Axiom) -> Result<AudioBuffer, String> {
+    let tuning = resolve_tuning(tree, &HashMap::new())?;
+    let mut errors = vec![];
+    let buffer = build_bar(tree, &tuning, &mut PlaybackState::default(), &mut errors)?;
+    match errors.into_iter().next() {
+        Some(error) => Err(error.message),
+        None => Ok(buffer),
+    }
+}
+
+/// Problems of the blocks that cannot be played in the bar `state` would
+/// render next, found by rendering it aside.
+pub fn check_bar(tree : &Axiom, tuning : &Tuning, state : &PlaybackState) -> Vec<Diagnostic> {
+    let mut state = PlaybackState { tail : vec![], ..*state };
+    let mut errors = vec![];
+    if let Err(message) = build_bar(tree, tuning, &mut state, &mut errors) {
+        errors.push(Diagnostic::error(message, Default::default()));
+    }
+
+    errors
+}
+
+/// Tuning asked for by the code, `custom` tunings first, with its reference.
+pub fn resolve_tuning(tree : &Axiom, custom : &HashMap<String, Tuning>) -> Result<Tuning, String> {
+    let tuning = match &tree.tuning {
+        Some(tuning) => Tuning::resolve(&tuning.name, custom)?,
+        None => Tuning::default(),
+    };
+
+    Ok(match tree.reference {
+        Some(frequency) => tuning.with_reference(frequency),
+        None => tuning,
+    })
+}
+
+/// Renders the next bar, mixing in what is left of the previous one.
+/// The blocks that cannot be played are skipped, with their errors pushed to
+/// `errors`. On success, `state` is updated for the following bar.
+pub fn build_bar(tree : &Axiom, tuning : &Tuning, state : &mut PlaybackState, errors : &mut Vec<Diagnostic>) -> Result<AudioBuffer, String> {
+    let buffer : Vec<f32> = vec![0.;(crate::SAMPLE_RATE * bar_duration(tree)) as usize];
 
     let mut context = BarContext {
         buffer,
-        beat_count,
-        beat_duration,
-        spb
+        tail : vec![],
+        pulses : tree.signature.pulses(),
+        tempo : tree.tempo,
+        length : tree.signature.length(),
+        tuning : tuning.clone(),
+        key : tree.key,
+        swing : tree.swing.clone(),
+        bar_index : state.bar_index,
+        rng : bar_rng(state.seed, state.bar_index),
+        errors : vec![],
     };
 
-    play_blocks(&mut context, tree.blocks, &vec![])?;
+    insert_samples(&mut context, &state.tail, 0);
+    play_blocks(&mut context, &tree.blocks, &[])?;
+    if let Some(section) = section_at(tree, state.bar_index) {
+        play_blocks(&mut context, &section.blocks, &[])?;
+    }
+
+    state.bar_index += 1;
+    state.tail = context.tail;
+    errors.append(&mut context.errors);
     Ok(context.buffer)
 }
 
-fn play_blocks(context : &mut BarContext, blocks : Vec<Block>, filters : &Vec<Filter>) -> Result<(), String> {
+/// Renders the whole song once from silence, with what rings past its last
+/// bar. The random notes are drawn from `seed` and the blocks that cannot be
+/// played are skipped, as when played live.
+pub fn build_song(tree : &Axiom, tuning : &Tuning, seed : u64) -> Result<AudioBuffer, String> {
+    let mut state = PlaybackState {seed, ..Default::default()};
+    let mut song = Vec::new();
+    for _ in 0..song_length(tree) {
+        song.extend(build_bar(tree, tuning, &mut state, &mut vec![])?);
+    }
+    song.extend(state.tail);
+
+    Ok(song)
+}
+
+/// Number of bars of the song, the sections being played in the order they
+/// are written when there is no `song`.
+pub fn song_length(tree : &Axiom) -> u32 {
+    let length = match &tree.song {
+        Some(song) => song.len(),
+        None => sections(tree).count(),
+    };
+
+    length.max(1) as u32
+}
+
+/// Section played in bar `bar_index`, the song starting over when it ends.
+/// Blocks outside of the sections are played in every bar.
+pub fn section_at(tree : &Axiom, bar_index : u32) -> Option<&Section> {
+    let index = (bar_index % song_length(tree)) as usize;
+    match &tree.song {
+        Some(song) => sections(tree).find(|section| section.name == song[index].name),
+        None => sections(tree).nth(index),
+    }
+}
+
+fn sections(tree : &Axiom) -> impl Iterator<Item = &Section> {
+    tree.blocks.iter().filter_map(|block| match block {
+        Block::Section(section) => Some(section),
+        _ => None,
+    })
+}
+
+/// Duration of a bar in seconds. The tempo counts quarter notes, whatever
+/// the time signature.
+pub fn bar_duration(tree : &Axiom) -> f32 {
+    let length = tree.signature.length();
+    tree.tempo.seconds(length, length)
+}
+
+fn play_blocks(context : &mut BarContext, blocks : &[Block], filters : &[Filter]) -> Result<(), String> {
     
     for block in blocks {
-        play_block(context, block, &filters)?;
+        play_block(context, block, filters)?;
     }
     
     Ok(())
 }
 
-fn play_block(context : &mut BarContext, block : Block, filters : &Vec<Filter>) -> Result<(), String> {
+fn play_block(context : &mut BarContext, block : &Block, filters : &[Filter]) -> Result<(), String> {
 
     match block {
         Block::Recursive(recursive_block) => play_recursive_block(context, recursive_block, filters)?,
-        Block::Instrument(instrument_block) => play_instrument_block(context, instrument_block, filters)?,
+        // A block that cannot be played is skipped, not the whole bar
+        Block::Instrument(instrument_block) => if let Err(message) = play_instrument_block(context, instrument_block, filters) {
+            context.errors.push(Diagnostic::error(message, instrument_block.span));
+        },
+        // Names are replaced by the resolver
+        Block::Let(binding) => return Err(format!("Unresolved binding : {}", binding.name)),
+        Block::Reference(reference) => return Err(format!("Unresolved name : {}", reference.name)),
+        Block::Def(def) => return Err(format!("Unresolved definition : {}", def.name)),
+        // Calls are expanded by the evaluator
+        Block::Call(call) => return Err(format!("Unevaluated call : {}", call.name)),
+        // Played by `build_bar` in the bars the song gives to it
+        Block::Section(_) => {},
     }
 
     Ok(())
 }
 
-fn play_recursive_block(context : &mut BarContext, block : RecBlock, filters : &Vec<Filter>) -> Result<(), String> {
+fn play_recursive_block(context : &mut BarContext, block : &RecBlock, filters : &[Filter]) -> Result<(), String> {
     
-    play_blocks(context, block.blocks, &[&filters[..], &block.filters[..]].concat())?;
+    play_blocks(context, &block.blocks, &[filters, &block.filters[..]].concat())?;
 
     Ok(())
 }
 
-fn play_instrument_block(context : &mut BarContext, instrument : Instrument, filters : &Vec<Filter>) -> Result<(), String> {
+fn play_instrument_block(context : &mut BarContext, instrument : &Instrument, filters : &[Filter]) -> Result<(), String> {
     
-    if instrument.notes.len() != context.beat_count.into() {
-        return Err(format!("Invalid number of notes. Found {}, expected {}", instrument.notes.len(), context.beat_count));
+    let pulse_count = total_weight(&instrument.notes)?;
+    if pulse_count as usize != context.pulses.len() {
+        return Err(format!("Invalid number of notes. Found {}, expected {}", pulse_count, context.pulses.len()));
     }
 
-    for (i, note) in instrument.notes.into_iter().enumerate() {
-        play_note(context, note, &instrument.instrument[..], filters, i)?;
+    let (transforms, filters) : (Vec<Filter>, Vec<Filter>) = [filters, &instrument.filters[..]].concat()
+        .into_iter()
+        .partition(|filter| TRANSFORMS.contains(&&filter.name[..]));
+    // The innermost groove wins
+    let (grooves, filters) : (Vec<Filter>, Vec<Filter>) = filters.into_iter().partition(|filter| filter.name == "swing");
+    let groove = match grooves.last() {
+        Some(filter) => Some(groove_arg(filter)?),
+        None => context.swing.clone(),
+    };
+    let notes = {
+        let mut pattern = PatternContext {bar_index : context.bar_index, key : context.key.as_ref(), rng : &mut context.rng};
+        let notes = choose_alternatives(instrument.notes.clone(), context.bar_index);
+        let notes = draw_notes(notes, pattern.rng)?;
+        transform_notes(notes, &transforms, &mut pattern)?
+    };
+    for event in schedule_notes(&notes, &context.pulses, context.key.as_ref())? {
+        let (start, velocity) = match &groove {
+            Some(groove) => groove.shift(event.start),
+            None => (event.start, 1.),
+        };
+        play_note(context, &event.pitches, &instrument.instrument[..], &filters, start, event.length, velocity)?;
     }
     
     Ok(())
 }
 
-fn play_note(context : &mut BarContext, note : Note, instrument : &str, filters : &Vec<Filter>, position : usize) -> Result<(), String> {
-    let sound = match instrument {
-        "simple" => play_a(note)?,
-        _ => return Err(format!("Unknown instrument name : {}",instrument))?,
+/// Operators written among the filters that rewrite the notes of the blocks
+/// they apply to, rather than their sound
+const TRANSFORMS : [&str; 6] = ["transpose", "rev", "rotate", "shuffle", "every", "when"];
+
+/// What the notes of an instrument depend on in a bar, besides the code
+struct PatternContext<'a> {
+    bar_index : u32,
+    key : Option<&'a Key>,
+    rng : &'a mut Rng,
+}
+
+/// Replaces each alternation by its alternative for turn `turn`, the
+/// alternatives being played in turn from one bar to the next. An alternation
+/// nested in another one takes a turn each time the outer one chooses it.
+fn choose_alternatives(notes : Vec<Note>, turn : u32) -> Vec<Note> {
+    notes.into_iter().map(|note| match note.kind {
+        NoteKind::Alternation(mut alternatives) => {
+            let count = alternatives.len() as u32;
+            let chosen = alternatives.swap_remove((turn % count) as usize);
+            let chosen = Note::single(choose_alternatives(vec![chosen], turn / count));
+            Note {kind : chosen.kind, weight : note.weight}
+        },
+        NoteKind::Group(children) => Note {kind : NoteKind::Group(choose_alternatives(children, turn)), ..note},
+        NoteKind::Maybe(maybe, probability) => {
+            let maybe = Note::single(choose_alternatives(vec![*maybe], turn));
+            Note {kind : NoteKind::Maybe(Box::new(maybe), probability), ..note}
+        },
+        NoteKind::Euclid(euclid) => {
+            let hit = Note::single(choose_alternatives(vec![*euclid.note], turn));
+            Note {kind : NoteKind::Euclid(Euclid {note : Box::new(hit), ..euclid}), ..note}
+        },
+        _ => note,
+    }).collect()
+}
+
+/// Draws the random notes of the bar: a note with a probability is kept or
+/// replaced by a rest, and `rand` pitches are drawn in their range.
+fn draw_notes(notes : Vec<Note>, rng : &mut Rng) -> Result<Vec<Note>, String> {
+    notes.into_iter().map(|note| {
+        let kind = match note.kind {
+            NoteKind::Maybe(maybe, probability) if rng.f32() < probability => Note::single(draw_notes(vec![*maybe], rng)?).kind,
+            NoteKind::Maybe(..) => NoteKind::Rest,
+            NoteKind::Random(Expr::Value(low), Expr::Value(high)) if low <= high => NoteKind::Pitch(Pitch(rng.i32(low..=high))),
+            NoteKind::Random(Expr::Value(low), Expr::Value(high)) => return Err(format!("Empty range : rand({}, {})", low, high)),
+            NoteKind::Random(..) => return Err("Unevaluated expression".to_string()),
+            NoteKind::Group(children) => NoteKind::Group(draw_notes(children, rng)?),
+            // Drawn once for all the hits
+            NoteKind::Euclid(euclid) => NoteKind::Euclid(Euclid {note : Box::new(Note::single(draw_notes(vec![*euclid.note], rng)?)), ..euclid}),
+            kind => kind,
+        };
+        Ok(Note {kind, ..note})
+    }).collect()
+}
+
+/// Applies `transforms` to `notes` in order.
+fn transform_notes(notes : Vec<Note>, transforms : &[Filter], pattern : &mut PatternContext) -> Result<Vec<Note>, String> {
+    let mut notes = notes;
+    for transform in transforms {
+        notes = transform_notes_once(notes, transform, pattern)?;
+    }
+
+    Ok(notes)
+}
+
+fn transform_notes_once(notes : Vec<Note>, transform : &Filter, pattern : &mut PatternContext) -> Result<Vec<Note>, String> {
+    match &transform.name[..] {
+        // Shifts the pitches by a number of semitones, degrees being resolved in the key first
+        "transpose" => {
+            let args = bind_args(transform, &["semitones"])?;
+            let semitones = required(transform, "semitones", integer_arg(transform, "semitones", args[0])?)?;
+            let semitones : i32 = semitones.try_into()
+                .map_err(|_| "Parameter `semitones` of transpose is out of range".to_string())?;
+            transpose(notes, semitones, pattern.key)
+        },
+        // Plays the notes backwards, holds staying after the note they hold
+        "rev" => {
+            bind_args(transform, &[])?;
+            Ok(reverse(notes))
+        },
+        // Starts the bar that many notes later, the first notes moving to its end
+        "rotate" => {
+            let args = bind_args(transform, &["steps"])?;
+            let steps = required(transform, "steps", integer_arg(transform, "steps", args[0])?)?;
+            let mut notes = notes;
+            if !notes.is_empty() {
+                let steps = steps.rem_euclid(notes.len() as i64) as usize;
+                notes.rotate_left(steps);
+            }
+            Ok(notes)
+        },
+        // Plays the notes in a random order, holds staying after the note they hold
+        "shuffle" => {
+            bind_args(transform, &[])?;
+            let mut runs = held_runs(notes);
+            pattern.rng.shuffle(&mut runs);
+            Ok(runs.into_iter().flatten().collect())
+        },
+        // Applies the transform written after the period on every bar whose
+        // index is a multiple of it, or plays the notes only on those bars
+        "every" => {
+            let (period, inner) = transform.args.split_first()
+                .ok_or_else(|| format!("Missing parameter `period` for {}", transform.name))?;
+            let period = integer_arg(transform, "period", Some(&period.value))?.unwrap_or_default();
+            if period < 1 {return Err("Period of every must be at least 1 !".to_string())}
+            conditionally(notes, pattern.bar_index as i64 % period == 0, transform, inner, "every:4 rev", pattern)
+        },
+        // Same with a condition on the bar number, such as `bar%8==7`
+        "when" => {
+            let (condition, inner) = transform.args.split_first()
+                .ok_or_else(|| format!("Missing parameter `condition` for {}", transform.name))?;
+            let holds = match &condition.value {
+                FilterValue::Condition(condition) => condition.holds(pattern.bar_index),
+                _ => return Err("Parameter `condition` of when must be a condition, e.g. `bar%8==7`".to_string()),
+            };
+            conditionally(notes, holds, transform, inner, "when:bar%8==7 rev", pattern)
+        },
+        _ => Err(format!("Unknown transform name : {}", transform.name)),
+    }
+}
+
+/// Applies the transform written in `inner` if `holds`, or without one plays
+/// the notes only if `holds`.
+fn conditionally(notes : Vec<Note>, holds : bool, transform : &Filter, inner : &[FilterArg], example : &str, pattern : &mut PatternContext) -> Result<Vec<Note>, String> {
+    let inner = match inner.split_first() {
+        None if holds => return Ok(notes),
+        None => return Ok(notes.into_iter().map(|note| Note {kind : NoteKind::Rest, ..note}).collect()),
+        Some((FilterArg {name : None, value : FilterValue::Ident(name)}, args)) if TRANSFORMS.contains(&&name[..]) => Filter {
+            name : name.clone(),
+            args : args.to_vec(),
+            span : transform.span,
+        },
+        _ => return Err(format!("{} applies a transform, e.g. `{}`", transform.name, example)),
     };
 
+    if holds {
+        transform_notes_once(notes, &inner, pattern)
+    }
+    else {
+        Ok(notes)
+    }
+}
+
+fn transpose(notes : Vec<Note>, semitones : i32, key : Option<&Key>) -> Result<Vec<Note>, String> {
+    let shift = |pitch : i32| pitch.checked_add(semitones).map(Pitch)
+        .ok_or_else(|| "Parameter `semitones` of transpose is out of range".to_string());
+    notes.into_iter().map(|note| {
+        let kind = match note.kind {
+            NoteKind::Pitch(pitch) => NoteKind::Pitch(shift(pitch.0)?),
+            NoteKind::Chord(pitches) => NoteKind::Chord(pitches.into_iter().map(|pitch| shift(pitch.0)).collect::<Result<_, _>>()?),
+            NoteKind::Degree(degree) => {
+                let key = key.ok_or("Scale degrees need a key, e.g. `key C major`")?;
+                NoteKind::Pitch(shift(degree_key(key, degree)?)?)
+            },
+            NoteKind::ChordDegree(chord) => {
+                let key = key.ok_or("Chord symbols need a key, e.g. `key C major`")?;
+                NoteKind::Chord(chord_keys(key, chord)?.into_iter().map(shift).collect::<Result<_, _>>()?)
+            },
+            NoteKind::Group(children) => NoteKind::Group(transpose(children, semitones, key)?),
+            NoteKind::Euclid(euclid) => {
+                let hit = Note::single(transpose(vec![*euclid.note], semitones, key)?);
+                NoteKind::Euclid(Euclid {note : Box::new(hit), ..euclid})
+            },
+            kind => kind,
+        };
+        Ok(Note {kind, ..note})
+    }).collect()
+}
+
+/// Reverses the notes and their groups, see `held_runs`.
+fn reverse(notes : Vec<Note>) -> Vec<Note> {
+    let notes = notes.into_iter().map(|note| match note.kind {
+        NoteKind::Group(children) => Note {kind : NoteKind::Group(reverse(children)), ..note},
+        _ => note,
+    });
+
+    held_runs(notes).into_iter().rev().flatten().collect()
+}
+
+/// Splits the notes into runs of a note and the holds that follow it, so that
+/// notes keep their length when moved. Holds with no note before them in
+/// their list become rests.
+fn held_runs(notes : impl IntoIterator<Item = Note>) -> Vec<Vec<Note>> {
+    let mut runs : Vec<Vec<Note>> = Vec::new();
+    for note in notes {
+        match (&note.kind, runs.last_mut()) {
+            (NoteKind::Hold, Some(run)) => run.push(note),
+            (NoteKind::Hold, None) => runs.push(vec![Note {kind : NoteKind::Rest, ..note}]),
+            _ => runs.push(vec![note]),
+        }
+    }
+
+    runs
+}
+
+/// Note or chord to be played, times in quarter notes from the start of the bar
+#[derive(Debug, PartialEq)]
+struct NoteEvent {
+    pitches : Vec<Pitch>,
+    start : f32,
+    length : f32,
+}
+
+/// Times the notes of an instrument, each top-level note lasting as many
+/// `pulses` as its weight. Each group shares its time among its notes
+/// according to their weights, and holds lengthen the note before them.
+/// Scale degrees and chord symbols are resolved in `key`.
+fn schedule_notes(notes : &[Note], pulses : &[f32], key : Option<&Key>) -> Result<Vec<NoteEvent>, String> {
+    let mut leaves = Vec::new();
+    let mut pulse = 0;
+    let mut position = 0.;
+    for note in notes {
+        let next_pulse = pulse + note.weight as usize;
+        let note_length : f32 = pulses[pulse..next_pulse].iter().sum();
+        flatten_notes(std::slice::from_ref(note), position, note_length, &mut leaves)?;
+        pulse = next_pulse;
+        position += note_length;
+    }
+
+    let mut events = Vec::new();
+    let mut current : Option<NoteEvent> = None;
+    for (kind, start, length) in leaves {
+        match kind {
+            NoteKind::Pitch(pitch) => {
+                events.extend(current.take());
+                current = Some(NoteEvent {pitches : vec![*pitch], start, length});
+            },
+            NoteKind::Chord(pitches) => {
+                events.extend(current.take());
+                current = Some(NoteEvent {pitches : pitches.clone(), start, length});
+            },
+            NoteKind::Degree(degree) => {
+                let key = key.ok_or("Scale degrees need a key, e.g. `key C major`")?;
+                events.extend(current.take());
+                current = Some(NoteEvent {pitches : vec![Pitch(degree_key(key, *degree)?)], start, length});
+            },
+            NoteKind::ChordDegree(chord) => {
+                let key = key.ok_or("Chord symbols need a key, e.g. `key C major`")?;
+                events.extend(current.take());
+                current = Some(NoteEvent {pitches : chord_keys(key, *chord)?.into_iter().map(Pitch).collect(), start, length});
+            },
+            // A hold after a rest holds the silence
+            NoteKind::Hold => if let Some(event) = current.as_mut() {
+                event.length += length;
+            },
+            NoteKind::Rest => events.extend(current.take()),
+            NoteKind::Group(_) | NoteKind::Euclid(_) => unreachable!("Groups and rhythms are flattened"),
+            NoteKind::Reference(reference) => return Err(format!("Unresolved name : {}", reference.name)),
+            NoteKind::Expr(_) => return Err("Unevaluated expression".to_string()),
+            NoteKind::Call(call) => return Err(format!("Unevaluated call : {}", call.name)),
+            NoteKind::Alternation(_) => return Err("Alternations are chosen before scheduling".to_string()),
+            NoteKind::Maybe(..) | NoteKind::Random(..) => return Err("Random notes are drawn before scheduling".to_string()),
+        }
+    }
+    events.extend(current);
+
+    Ok(events)
+}
+
+fn degree_key(key : &Key, degree : u32) -> Result<i32, String> {
+    key.degree(degree as i64).ok_or_else(|| format!("Scale degree ^{} is out of range", degree))
+}
+
+fn chord_keys(key : &Key, chord : ChordDegree) -> Result<Vec<i32>, String> {
+    key.chord(chord).ok_or_else(|| "Chord symbol is out of range".to_string())
+}
+
+/// Lists the notes that are not groups, with their start and length. The
+/// steps of a euclidean rhythm are listed as its note or a rest.
+fn flatten_notes<'a>(notes : &'a [Note], start : f32, length : f32, leaves : &mut Vec<(&'a NoteKind, f32, f32)>) -> Result<(), String> {
+    let total_weight = total_weight(notes)?;
+    let mut position = start;
+    for note in notes {
+        let note_length = length * note.weight as f32 / total_weight as f32;
+        match &note.kind {
+            NoteKind::Group(children) => flatten_notes(children, position, note_length, leaves)?,
+            NoteKind::Euclid(euclid) => {
+                let steps = euclidean_rhythm(euclid)?;
+                let step_length = note_length / steps.len() as f32;
+                for (step, hit) in steps.into_iter().enumerate() {
+                    let step_start = position + step as f32 * step_length;
+                    if hit {
+                        flatten_notes(std::slice::from_ref(&euclid.note), step_start, step_length, leaves)?;
+                    }
+                    else {
+                        leaves.push((&NoteKind::Rest, step_start, step_length));
+                    }
+                }
+            },
+            kind => leaves.push((kind, position, note_length)),
+        }
+        position += note_length;
+    }
+
+    Ok(())
+}
+
+/// Sum of the weights of `notes`.
+fn total_weight(notes : &[Note]) -> Result<u32, String> {
+    notes.iter().try_fold(0u32, |total, note| total.checked_add(note.weight))
+        .ok_or_else(|| "The weights of the notes add up to too much".to_string())
+}
+
+/// Steps of the rhythm, `true` for a hit.
+fn euclidean_rhythm(euclid : &Euclid) -> Result<Vec<bool>, String> {
+    match (&euclid.hits, &euclid.steps, &euclid.rotation) {
+        (Expr::Value(hits), Expr::Value(steps), Expr::Value(rotation)) if 0 <= *hits && hits <= steps => {
+            let mut rhythm = bjorklund(*hits as usize, *steps as usize);
+            if !rhythm.is_empty() {
+                let rotation = rotation.rem_euclid(rhythm.len() as i32) as usize;
+                rhythm.rotate_left(rotation);
+            }
+            Ok(rhythm)
+        },
+        (Expr::Value(hits), Expr::Value(steps), Expr::Value(_)) => Err(format!("Cannot place {} hits on {} steps", hits, steps)),
+        _ => Err("Unevaluated expression".to_string()),
+    }
+}
+
+/// Spreads `hits` over `steps` with Bjorklund's algorithm: the hits and the
+/// rests start as sequences of their own, and the remaining sequences are
+/// appended to the first ones until one side has at most one of them.
+fn bjorklund(hits : usize, steps : usize) -> Vec<bool> {
+    let mut sequences = vec![vec![true]; hits];
+    let mut remainders = vec![vec![false]; steps - hits];
+    while min(sequences.len(), remainders.len()) > 1 {
+        let paired = min(sequences.len(), remainders.len());
+        let left = if sequences.len() > paired {
+            sequences.split_off(paired)
+        }
+        else {
+            remainders.split_off(paired)
+        };
+        for (sequence, remainder) in sequences.iter_mut().zip(remainders) {
+            sequence.extend(remainder);
+        }
+        remainders = left;
+    }
+
+    sequences.into_iter().chain(remainders).flatten().collect()
+}
+
+/// Plays `pitches` together from `start`, for `length`, in quarter notes,
+/// `velocity` scaling their gain.
+fn play_note(context : &mut BarContext, pitches : &[Pitch], instrument : &str, filters : &[Filter], start : f32, length : f32, velocity : f32) -> Result<(), String> {
+    let duration = (context.seconds(start + length) - context.seconds(start)) * GATE;
+    // Chords are as loud as single notes
+    let gain = velocity / (pitches.len() as f32).sqrt();
+
+    let mut sound : AudioBuffer = vec![];
+    for &pitch in pitches {
+        let frequency = context.tuning.frequency(pitch)
+            .ok_or_else(|| format!("Key {} is not mapped in the tuning", pitch.0))?;
+        let voice = match instrument {
+            "simple" => play_a(frequency, duration)?,
+            "noise" => play_noise(frequency, duration, &mut context.rng)?,
+            _ => return Err(format!("Unknown instrument name : {}",instrument))?,
+        };
+        if sound.len() < voice.len() {
+            sound.resize(voice.len(), 0.);
+        }
+        for (sample, value) in sound.iter_mut().zip(voice) {
+            *sample += gain * value;
+        }
+    }
+
     let sound_ = apply_filters(sound, filters)?;
 
-    insert_sound(context, sound_, position);
+    insert_sound(context, sound_, start);
     
     Ok(())
 }
 
-fn play_a(note : Note) -> Result<AudioBuffer,String> {
-    let frequency = pitch_to_frequency(note.pitch);
-    
-    let buffer = AudioBuffer::square_wave(35_300, frequency);
-    println!("playa1 {:?}",&buffer[buffer.len()-100..]);
-    let buffer = buffer.low_pass(3.*frequency as f32);
-    println!("playa2 {:?}",&buffer[buffer.len()-100..]);
-    let buffer = buffer.adsr(0.5, 0.05, 0.1, 0.7, 0.3)?;
-    println!("playa3 {:?}",&buffer[buffer.len()-100..]);
-    Ok(buffer)
+/// Time during which the voices fade out after being released, in seconds
+const RELEASE : f32 = 0.3;
+
+fn play_a(frequency : f32, duration : f32) -> Result<AudioBuffer,String> {
+    let buffer = AudioBuffer::square_wave(voice_size(duration), frequency);
+    let buffer = buffer.low_pass(3.*frequency);
+    envelope(buffer, duration)
+}
+
+/// White noise, brighter for higher pitches
+fn play_noise(frequency : f32, duration : f32, rng : &mut Rng) -> Result<AudioBuffer,String> {
+    let buffer = AudioBuffer::white_noise(voice_size(duration), rng);
+    let buffer = buffer.low_pass(8.*frequency);
+    envelope(buffer, duration)
 }
 
-fn pitch_to_frequency(pitch : usize) -> usize {
-    const DO : f32 = 261.63;
-    const LOG_STEP : f32 = 1.0594630943;
-    (DO * LOG_STEP.powi((pitch%12).try_into().unwrap())) as usize >> pitch/12
+/// Number of samples of a voice held for `duration` seconds, then released
+fn voice_size(duration : f32) -> usize {
+    ((duration + RELEASE) * crate::SAMPLE_RATE) as usize + 1
 }
 
+fn envelope(buffer : AudioBuffer, duration : f32) -> Result<AudioBuffer,String> {
+    // Short notes get a shorter attack and decay
+    let attack = 0.05f32.min(duration / 3.);
+    let decay = 0.1f32.min(duration / 3.);
+
+    buffer.adsr(duration, attack, decay, 0.7, RELEASE)
+}
 
-fn apply_filters(sound : AudioBuffer, filters : &Vec<Filter>) -> Result<AudioBuffer, String> {
+fn apply_filters(sound : AudioBuffer, filters : &[Filter]) -> Result<AudioBuffer, String> {
     let mut sound_ : AudioBuffer = sound;
     for filter in filters {
         sound_ = apply_filter(sound_, filter)?;
@@ -109,22 +657,36 @@ fn apply_filters(sound : AudioBuffer, filters : &Vec<Filter>) -> Result<AudioBuf
     Ok(sound_)
 }
 
+/// Longest delay of an echo, in ms
+const MAX_ECHO_DELAY : f32 = 5000.;
 
 fn apply_filter(sound : AudioBuffer, filter : &Filter) -> Result<AudioBuffer, String> {
     match &filter.name[..] {
-        // Low-pass filter
+        // Low-pass filter, resonant if given a `q`
         "lp" => {
-            let cutoff = filter.value;
-            if cutoff < 0 {return Err("Cut-off frequency must be positive !".to_string())}
-            let sound_ = sound.low_pass(cutoff as f32);
-            
+            let args = bind_args(filter, &["cutoff", "q"])?;
+            let cutoff = required(filter, "cutoff", number_arg(filter, "cutoff", args[0], Quantity::Frequency)?)?;
+            if cutoff < 0. {return Err("Cut-off frequency must be positive !".to_string())}
+            // Past the Nyquist frequency the resonant filter diverges
+            if cutoff >= crate::SAMPLE_RATE / 2. {
+                return Err(format!("Cut-off frequency must be below {}hz !", crate::SAMPLE_RATE / 2.));
+            }
+            let sound_ = match number_arg(filter, "q", args[1], Quantity::Ratio)? {
+                Some(q) if q <= 0. => return Err("Resonance must be positive !".to_string()),
+                Some(q) => sound.resonant_low_pass(cutoff, q),
+                None => sound.low_pass(cutoff),
+            };
 
             Ok(sound_)
         }
         "echo" => {
-            let delta = filter.value;
-            if delta < 0 {return Err("Delta value must be positive !".to_string())}
-            let sound_ = sound.echo(delta as f32, 0.8);
+            let args = bind_args(filter, &["time", "feedback"])?;
+            let delta = required(filter, "time", number_arg(filter, "time", args[0], Quantity::Duration)?)?;
+            if delta < 0. {return Err("Delta value must be positive !".to_string())}
+            if delta > MAX_ECHO_DELAY {return Err(format!("Delay must be below {}ms !", MAX_ECHO_DELAY))}
+            let feedback = number_arg(filter, "feedback", args[1], Quantity::Ratio)?.unwrap_or(0.8);
+            if !(0. ..=1.).contains(&feedback) {return Err("Feedback must be between 0 and 1 !".to_string())}
+            let sound_ = sound.echo(delta, feedback);
 
             Ok(sound_)
         }
@@ -132,11 +694,622 @@ fn apply_filter(sound : AudioBuffer, filter : &Filter) -> Result<AudioBuffer, St
     }
 }
 
-fn insert_sound(context : &mut BarContext, sound : AudioBuffer, position : usize) {
+/// What a numeric parameter measures, which tells the units it accepts
+#[derive(Clone, Copy)]
+enum Quantity {
+    /// In hz
+    Frequency,
+    /// In ms
+    Duration,
+    /// Plain number or percentage
+    Ratio,
+    /// Whole number, without unit
+    Count,
+}
+
+/// Values of the arguments of `filter` by parameter, in the order of
+/// `params`. Positional arguments take the parameters in order, named ones
+/// the parameter with their name.
+fn bind_args<'a>(filter : &'a Filter, params : &[&str]) -> Result<Vec<Option<&'a FilterValue>>, String> {
+    if params.is_empty() && !filter.args.is_empty() {
+        return Err(format!("{} takes no arguments", filter.name));
+    }
+    let mut values = vec![None; params.len()];
+    let mut position = 0;
+    for arg in &filter.args {
+        let index = match &arg.name {
+            Some(name) => params.iter().position(|param| param == name).ok_or_else(|| format!(
+                "Unknown parameter `{}` for {}, expected {}", name, filter.name, params.join(", ")
+            ))?,
+            None => {
+                position += 1;
+                position - 1
+            },
+        };
+        if index >= params.len() {
+            return Err(format!("Too many arguments for {}, expected {}", filter.name, params.join(", ")));
+        }
+        if values[index].is_some() {
+            return Err(format!("Parameter `{}` of {} is given twice", params[index], filter.name));
+        }
+        values[index] = Some(&arg.value);
+    }
+
+    Ok(values)
+}
+
+/// Number given for `param`, converted to the unit of its quantity.
+fn number_arg(filter : &Filter, param : &str, value : Option<&FilterValue>, quantity : Quantity) -> Result<Option<f32>, String> {
+    let (number, unit) = match value {
+        None => return Ok(None),
+        Some(FilterValue::Number(number, unit)) => (*number as f32, *unit),
+        Some(_) => return Err(format!("Parameter `{}` of {} must be a number", param, filter.name)),
+    };
+
+    match (quantity, unit) {
+        (_, None) | (Quantity::Frequency, Some(Unit::Hertz)) | (Quantity::Duration, Some(Unit::Milliseconds)) => Ok(Some(number)),
+        (Quantity::Ratio, Some(Unit::Percent)) => Ok(Some(number / 100.)),
+        (_, Some(unit)) => Err(format!("Parameter `{}` of {} cannot be in {}", param, filter.name, unit)),
+    }
+}
+
+/// Whole number given for `param`.
+fn integer_arg(filter : &Filter, param : &str, value : Option<&FilterValue>) -> Result<Option<i64>, String> {
+    match number_arg(filter, param, value, Quantity::Count)? {
+        Some(number) if number.fract() != 0. => Err(format!("Parameter `{}` of {} must be a whole number", param, filter.name)),
+        number => Ok(number.map(|number| number as i64)),
+    }
+}
+
+/// Groove of a `swing` filter, a percentage such as `<swing:60%>` or the
+/// name of a template such as `<swing:mpc16>`.
+fn groove_arg(filter : &Filter) -> Result<Groove, String> {
+    let args = bind_args(filter, &["amount"])?;
+    match required(filter, "amount", args[0])? {
+        FilterValue::Ident(name) => groove_template(name)
+            .ok_or_else(|| format!("Unknown groove : {}. Known grooves are mpc8, mpc16, shuffle and laid_back", name)),
+        value => {
+            let ratio = required(filter, "amount", number_arg(filter, "amount", Some(value), Quantity::Ratio)?)?;
+            Groove::swing(ratio).ok_or_else(|| "Swing is between 50% and 75%".to_string())
+        },
+    }
+}
+
+fn required<T>(filter : &Filter, param : &str, value : Option<T>) -> Result<T, String> {
+    value.ok_or_else(|| format!("Missing parameter `{}` for {}", param, filter.name))
+}
+
+/// Mixes `sound` into the bar from `start`, in quarter notes.
+fn insert_sound(context : &mut BarContext, sound : AudioBuffer, start : f32) {
+    let start_sample = (context.seconds(start) * crate::SAMPLE_RATE) as usize;
+
+    insert_samples(context, &sound, start_sample);
+}
+
+/// Mixes `sound` into the bar from `start_sample`, what goes past the end
+/// of the bar is kept in the tail for the next one.
+fn insert_samples(context : &mut BarContext, sound : &[f32], start_sample : usize) {
     let bufferlen = context.buffer.len();
-    let start_sample : usize =  bufferlen * position / context.beat_count as usize;
+    // A note can start past the end of the bar, when a groove delays it
+    let in_bar = min(sound.len(), bufferlen.saturating_sub(start_sample));
+
+    for (sample, value) in context.buffer.iter_mut().skip(start_sample).zip(sound) {
+        *sample += value;
+    }
+
+    let overflow = &sound[in_bar..];
+    if overflow.is_empty() {
+        return;
+    }
+    let tail_start = start_sample + in_bar - bufferlen;
+    if context.tail.len() < tail_start + overflow.len() {
+        context.tail.resize(tail_start + overflow.len(), 0.);
+    }
+    for (sample, value) in context.tail[tail_start..].iter_mut().zip(overflow) {
+        *sample += value;
+    }
+}
+
+
+
+/* *************TESTS*************** */
+
+
+#[cfg(test)]
+fn render(notes : &str) -> AudioBuffer {
+    let code = format!("bpm 90\n3/4\nsimple({})\n", notes);
+    let (axiom, diagnostics) = parse(code);
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    build_buffer(&axiom.unwrap()).unwrap()
+}
+
+/// Loudest sample around `beat`, at 90 bpm
+#[cfg(test)]
+fn level_at(buffer : &AudioBuffer, beat : f32) -> f32 {
+    let center = (beat * 60. / 90. * crate::SAMPLE_RATE) as usize;
+    buffer[center-500..center+500].iter().fold(0., |max, sample| sample.abs().max(max))
+}
+
+#[test]
+fn rests_are_silent() {
+    let buffer = render("_, ., _");
+
+    assert!(buffer.iter().all(|&sample| sample == 0.));
+}
+
+#[test]
+fn held_note_lasts_several_beats() {
+    let short = render("C4, _, _");
+    let held = render("C4, -, _");
+
+    assert!(level_at(&short, 0.5) > 0.1);
+    assert!(level_at(&short, 1.4) == 0.);
+    assert!(level_at(&held, 1.4) > 0.1);
+    assert!(level_at(&held, 2.8) == 0.);
+}
+
+#[test]
+fn higher_keys_sound_higher() {
+    for name in ["12edo", "19edo", "31edo", "just", "pythagorean"] {
+        let tuning = Tuning::builtin(name).unwrap();
+        for key in -12..140 {
+            assert!(tuning.frequency(Pitch(key + 1)) > tuning.frequency(Pitch(key)));
+        }
+    }
+}
+
+#[test]
+fn tuning_directive_changes_the_sound() {
+    let code = |tuning : &str| format!("bpm 90\n3/4\n{}\nsimple(C4, E4, G4)\n", tuning);
+    let (equal, _) = parse(code(""));
+    let (just, diagnostics) = parse(code("tuning just"));
+    assert!(diagnostics.is_empty());
+
+    assert_ne!(build_buffer(&equal.unwrap()).unwrap(), build_buffer(&just.unwrap()).unwrap());
+    let (unknown, _) = parse(code("tuning 0edo"));
+    assert_eq!(build_buffer(&unknown.unwrap()).unwrap_err(), "Unknown tuning : 0edo");
+}
+
+#[cfg(test)]
+fn schedule(notes : &str) -> Vec<(i32, f32, f32)> {
+    let (axiom, diagnostics) = parse(format!("bpm 90\n4/4\nsimple({})\n", notes));
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => schedule_notes(&instrument.notes, &[1.; 4], None).unwrap().into_iter()
+            .map(|event| (event.pitches[0].0, event.start, event.length))
+            .collect(),
+        _ => panic!("Expected an instrument"),
+    }
+}
+
+#[test]
+fn subdivisions() {
+    assert_eq!(schedule("1, [2 3], 4, 5"), vec![(1, 0., 1.), (2, 1., 0.5), (3, 1.5, 0.5), (4, 2., 1.), (5, 3., 1.)]);
+    assert_eq!(schedule("[1 2 3]@2, [4 [5 6]], _"), vec![
+        (1, 0., 2./3.), (2, 2./3., 2./3.), (3, 4./3., 2./3.),
+        (4, 2., 0.5), (5, 2.5, 0.25), (6, 2.75, 0.25),
+    ]);
+    assert_eq!(schedule("1@3, 2"), vec![(1, 0., 3.), (2, 3., 1.)]);
+}
+
+#[test]
+fn holds_across_subdivisions() {
+    assert_eq!(schedule("[1 -], [- 2], [_ -], 3"), vec![(1, 0., 1.5), (2, 1.5, 0.5), (3, 3., 1.)]);
+}
+
+#[test]
+fn short_notes_render() {
+    let code = "bpm 200\n2/4\nsimple([1 2 3 4 5 6 7 8], [[1 2] [3 4] [5 6] [7 8]])\n";
+    let (axiom, _) = parse(code.to_string());
+
+    assert!(build_buffer(&axiom.unwrap()).is_ok());
+}
+
+#[test]
+fn chords_are_normalized() {
+    let single = render("C4, _, _");
+    let unison = render("{C4 C4}, _, _");
+    let chord = render("C4maj, -, G4");
+
+    for (single, unison) in single.iter().zip(&unison) {
+        assert!((unison - 2f32.sqrt() * single).abs() < 1e-4);
+    }
+    assert!(level_at(&chord, 1.4) > 0.1);
+    assert!(chord.iter().all(|sample| sample.abs() < 3f32.sqrt()));
+}
+
+#[test]
+fn degrees_are_resolved_in_the_key() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nkey D minor\nsimple(^1, ^3, ^8, V7)\n".to_string());
+    assert!(diagnostics.is_empty());
+    let axiom = axiom.unwrap();
+    let events = match &axiom.blocks[0] {
+        Block::Instrument(instrument) => schedule_notes(&instrument.notes, &[1.; 4], axiom.key.as_ref()).unwrap(),
+        _ => panic!("Expected an instrument"),
+    };
+    let pitches : Vec<Vec<i32>> = events.iter()
+        .map(|event| event.pitches.iter().map(|pitch| pitch.0).collect())
+        .collect();
+    assert_eq!(pitches, vec![vec![62], vec![65], vec![74], vec![69, 73, 76, 79]]);
+    assert!(build_buffer(&axiom).is_ok());
+
+    let (axiom, _) = parse("bpm 90\n4/4\nsimple(^1, 2, 3, 4)\n".to_string());
+    assert_eq!(build_buffer(&axiom.unwrap()).unwrap_err(), "Scale degrees need a key, e.g. `key C major`");
+
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nkey C major\nsimple(^4294967295, 2, 3, 4)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    assert_eq!(build_buffer(&axiom.unwrap()).unwrap_err(), "Scale degree ^4294967295 is out of range");
+}
+
+#[test]
+fn bar_length_follows_the_signature() {
+    let bar = |signature : &str, notes : &str| {
+        let (axiom, diagnostics) = parse(format!("bpm 120\n{}\nsimple({})\n", signature, notes));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        build_buffer(&axiom.unwrap()).unwrap().len()
+    };
+    let second = crate::SAMPLE_RATE as usize;
+
+    assert_eq!(bar("4/4", "1, 2, 3, 4"), 2 * second);
+    assert_eq!(bar("7/4", "1, 2, 3, 4, 5, 6, 7"), 7 * second / 2);
+    assert_eq!(bar("7/8", "1, 2, 3, 4, 5, 6, 7"), 7 * second / 4);
+    assert_eq!(bar("6/8", "1, 2"), 3 * second / 2);
+    assert_eq!(bar("3+2+2/8", "1, 2, 3"), 7 * second / 4);
+}
+
+#[test]
+fn irregular_pulses() {
+    let (axiom, _) = parse("bpm 90\n3+2+2/8\nsimple(1, [2 3], 4)\n".to_string());
+    let axiom = axiom.unwrap();
+    let events = match &axiom.blocks[0] {
+        Block::Instrument(instrument) => schedule_notes(&instrument.notes, &axiom.signature.pulses(), None).unwrap(),
+        _ => panic!("Expected an instrument"),
+    };
+    let times : Vec<(f32, f32)> = events.iter().map(|event| (event.start, event.length)).collect();
+    assert_eq!(times, vec![(0., 1.5), (1.5, 0.5), (2., 0.5), (2.5, 1.)]);
+}
+
+#[test]
+fn tempo_ramps_shorten_the_notes() {
+    let (axiom, _) = parse("bpm 60 -> 120\n4/4\nsimple(1, 2, 3, 4)\n".to_string());
+    let axiom = axiom.unwrap();
+    let duration = bar_duration(&axiom);
+    assert!(duration < 4. && duration > 2.);
+    assert_eq!(build_buffer(&axiom).unwrap().len(), (duration * crate::SAMPLE_RATE) as usize);
+
+    let (axiom, _) = parse("bpm 128.5\n4/4\nsimple(1, 2, 3, 4)\n".to_string());
+    assert!((bar_duration(&axiom.unwrap()) - 240. / 128.5).abs() < 1e-5);
+}
+
+#[test]
+fn filter_parameters() {
+    let filter = |code : &str| {
+        let (axiom, diagnostics) = parse(format!("bpm 90\n3/4\nsimple<{}>(60, 64, 67)\n", code));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        build_buffer(&axiom.unwrap())
+    };
+
+    assert_eq!(filter("lp:800").unwrap(), filter("lp:cutoff=800hz").unwrap());
+    assert_ne!(filter("lp:800").unwrap(), filter("lp:800 q=4").unwrap());
+    assert_eq!(filter("echo:100 0.5").unwrap(), filter("echo:feedback=50% time=100ms").unwrap());
+
+    let error = |code| filter(code).unwrap_err();
+    assert_eq!(error("lp"), "Missing parameter `cutoff` for lp");
+    assert_eq!(error("lp:q=2"), "Missing parameter `cutoff` for lp");
+    assert_eq!(error("lp:800 2 3"), "Too many arguments for lp, expected cutoff, q");
+    assert_eq!(error("lp:800 res=2"), "Unknown parameter `res` for lp, expected cutoff, q");
+    assert_eq!(error("lp:800 cutoff=900"), "Parameter `cutoff` of lp is given twice");
+    assert_eq!(error("lp:800ms"), "Parameter `cutoff` of lp cannot be in ms");
+    assert_eq!(error("lp:22000 q=2"), "Cut-off frequency must be below 22000hz !");
+    assert_eq!(error("lp:30000"), "Cut-off frequency must be below 22000hz !");
+    assert_eq!(error("echo:\"long\""), "Parameter `time` of echo must be a number");
+    assert_eq!(error("echo:100 feedback=2"), "Feedback must be between 0 and 1 !");
+    assert_eq!(error("echo:1e12"), "Delay must be below 5000ms !");
+    assert_eq!(error("echo:time=1e9ms"), "Delay must be below 5000ms !");
+}
+
+#[test]
+fn resonance_boosts_the_cutoff() {
+    let sound : AudioBuffer = (0..44_000).map(|i| (i as f32 * 2. * std::f32::consts::PI * 1000. / crate::SAMPLE_RATE).sin()).collect();
+    let peak = |buffer : AudioBuffer| buffer[22_000..].iter().fold(0f32, |max, sample| max.max(sample.abs()));
+
+    assert!(peak(sound.resonant_low_pass(1000., 4.)) > 2.);
+    assert!(peak(sound.resonant_low_pass(1000., 0.5)) < 1.);
+}
+
+#[cfg(test)]
+fn transformed(notes : &str, transforms : &str, bar_index : u32) -> Result<Vec<(i32, f32, f32)>, String> {
+    let (axiom, diagnostics) = parse(format!("bpm 90\n4/4\nsimple<{}>({})\n", transforms, notes));
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    match &axiom.unwrap().blocks[0] {
+        Block::Instrument(instrument) => {
+            let mut rng = bar_rng(0, bar_index);
+            let notes = choose_alternatives(instrument.notes.clone(), bar_index);
+            let notes = draw_notes(notes, &mut rng)?;
+            let notes = transform_notes(notes, &instrument.filters, &mut PatternContext {bar_index, key : None, rng : &mut rng})?;
+            Ok(schedule_notes(&notes, &[1.; 4], None)?.into_iter()
+                .map(|event| (event.pitches[0].0, event.start, event.length))
+                .collect())
+        },
+        _ => panic!("Expected an instrument"),
+    }
+}
+
+#[test]
+fn transforms_rewrite_the_notes() {
+    let transform = |transforms| transformed("1, [2 3], -, 4", transforms, 0).unwrap();
+
+    assert_eq!(transformed("1, 2, 3, 4", "transpose:2147483647", 0).unwrap_err(), "Parameter `semitones` of transpose is out of range");
+    assert_eq!(transformed("1, 2, 3, 4", "transpose:2000000000, transpose:2000000000", 0).unwrap_err(), "Parameter `semitones` of transpose is out of range");
+    assert_eq!(transformed("1, 2, 3, 4", "transpose:1e12", 0).unwrap_err(), "Parameter `semitones` of transpose is out of range");
+
+    assert_eq!(transform("transpose:12"), vec![(13, 0., 1.), (14, 1., 0.5), (15, 1.5, 1.5), (16, 3., 1.)]);
+    assert_eq!(transform("transpose:-1"), vec![(0, 0., 1.), (1, 1., 0.5), (2, 1.5, 1.5), (3, 3., 1.)]);
+    assert_eq!(transform("rev"), vec![(4, 0., 1.), (3, 1., 0.5), (2, 1.5, 1.5), (1, 3., 1.)]);
+    assert_eq!(transform("rotate:1"), vec![(2, 0., 0.5), (3, 0.5, 1.5), (4, 2., 1.), (1, 3., 1.)]);
+    assert_eq!(transform("rotate:-1"), vec![(4, 0., 1.), (1, 1., 1.), (2, 2., 0.5), (3, 2.5, 1.5)]);
+    assert_eq!(transform("rev, transpose:12"), transform("transpose:12, rev"));
+
+    assert_eq!(transformed("[1 2]@2, 3, -", "rev", 0).unwrap(), vec![(3, 0., 2.), (2, 2., 1.), (1, 3., 1.)]);
+    assert_eq!(transformed("[- 1], 2, 3, 4", "rev", 0).unwrap(), vec![(4, 0., 1.), (3, 1., 1.), (2, 2., 1.), (1, 3., 0.5)]);
+}
+
+#[test]
+fn every_applies_on_some_bars() {
+    let notes = "1, 2, 3, 4";
+    let plain = transformed(notes, "transpose:0", 0).unwrap();
+    let reversed = transformed(notes, "rev", 0).unwrap();
+
+    let bars : Vec<bool> = (0..6).map(|bar| transformed(notes, "every:3 rev", bar).unwrap() == reversed).collect();
+    assert_eq!(bars, vec![true, false, false, true, false, false]);
+    assert_eq!(transformed(notes, "every:2 transpose 12", 1).unwrap(), plain);
+    assert_eq!(transformed(notes, "every:2 transpose 12", 2).unwrap(), transformed(notes, "transpose:12", 0).unwrap());
+
+    let error = |transforms| transformed(notes, transforms, 0).unwrap_err();
+    assert_eq!(error("every:0 rev"), "Period of every must be at least 1 !");
+    assert_eq!(error("every:2 lp 800"), "every applies a transform, e.g. `every:4 rev`");
+    assert_eq!(error("rev:1"), "rev takes no arguments");
+    assert_eq!(error("rotate"), "Missing parameter `steps` for rotate");
+    assert_eq!(error("transpose:1.5"), "Parameter `semitones` of transpose must be a whole number");
+    assert_eq!(error("transpose:12hz"), "Parameter `semitones` of transpose cannot be in hz");
+}
+
+#[test]
+fn transforms_apply_to_nested_blocks() {
+    let render = |code : &str| {
+        let (axiom, diagnostics) = parse(format!("bpm 90\n3/4\nkey C major\n{}\n", code));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        build_buffer(&axiom.unwrap()).unwrap()
+    };
+
+    assert_eq!(render("<transpose:12>(\n    simple<lp:800>(60, ^2, I)\n)"), render("simple<lp:800>(72, 74, {72 76 79})"));
+    assert_eq!(render("<rev>(\n    simple(60, 62, 64)\n)"), render("simple(64, 62, 60)"));
+}
+
+#[test]
+fn sections_follow_the_song() {
+    let code = std::fs::read_to_string("./tests/codebase/song.xfzd").expect("Impossible de lire le fichier");
+    let (axiom, diagnostics) = crate::code_parser::analyse(code.clone());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+
+    assert_eq!(song_length(&axiom), 5);
+    let names : Vec<Option<&str>> = (0..7).map(|bar| section_at(&axiom, bar).map(|section| &section.name[..])).collect();
+    assert_eq!(names, vec![Some("intro"), Some("verse"), Some("verse"), Some("chorus"), Some("verse"), Some("intro"), Some("verse")]);
+
+    let tuning = Tuning::default();
+    let bar = |bar_index| build_bar(&axiom, &tuning, &mut PlaybackState {bar_index, ..Default::default()}, &mut vec![]).unwrap();
+    assert_eq!(bar(1), bar(2));
+    assert_eq!(bar(1), bar(4));
+    assert_eq!(bar(0), bar(5));
+    assert_ne!(bar(0), bar(1));
+    assert_ne!(bar(1), bar(3));
+
+    let song = build_song(&axiom, &tuning, 0).unwrap();
+    assert!(song.len() > 5 * bar(0).len());
+    assert_eq!(song[..bar(0).len()], bar(0)[..]);
+
+    // Without a song, the sections are played in order
+    let (axiom, _) = crate::code_parser::analyse(code.replace("song intro verse*2 chorus verse\n", ""));
+    let axiom = axiom.unwrap();
+    assert_eq!(song_length(&axiom), 3);
+    assert_eq!(section_at(&axiom, 4).unwrap().name, "verse");
+
+    let (axiom, _) = parse("bpm 90\n4/4\nsimple(1, 2, 3, 4)\n".to_string());
+    let axiom = axiom.unwrap();
+    assert_eq!(song_length(&axiom), 1);
+    assert!(section_at(&axiom, 0).is_none());
+}
+
+#[test]
+fn alternations_change_every_bar() {
+    let pitches = |bar| transformed("1, (2 | 3 | 4), ((5 | 6) | 7), 8", "", bar).unwrap()
+        .into_iter().map(|(pitch, _, _)| pitch).collect::<Vec<_>>();
+
+    assert_eq!(pitches(0), vec![1, 2, 5, 8]);
+    assert_eq!(pitches(1), vec![1, 3, 7, 8]);
+    assert_eq!(pitches(2), vec![1, 4, 6, 8]);
+    assert_eq!(pitches(3), vec![1, 2, 7, 8]);
+    assert_eq!(pitches(4), vec![1, 3, 5, 8]);
+    assert_eq!(transformed("1, ([2 3] | 4)@2, 5", "", 0).unwrap(), vec![(1, 0., 1.), (2, 1., 1.), (3, 2., 1.), (5, 3., 1.)]);
+    assert_eq!(transformed("1, ([2 3] | 4)@2, 5", "", 1).unwrap(), vec![(1, 0., 1.), (4, 1., 2.), (5, 3., 1.)]);
 
-    for i in 0..min(sound.len(), bufferlen-start_sample) {
-        context.buffer[start_sample+i] += sound[i]; 
+    // The same bar always sounds the same
+    let (alternating, _) = parse("bpm 90\n3/4\nsimple((60 | 62), 64, 67)\n".to_string());
+    let (plain, _) = parse("bpm 90\n3/4\nsimple(62, 64, 67)\n".to_string());
+    let bar = |axiom : &Axiom, bar_index| build_bar(axiom, &Tuning::default(), &mut PlaybackState {bar_index, ..Default::default()}, &mut vec![]).unwrap();
+    let alternating = alternating.unwrap();
+    assert_eq!(bar(&alternating, 5), bar(&plain.unwrap(), 0));
+    assert_eq!(bar(&alternating, 5), bar(&alternating, 3));
+}
+
+#[test]
+fn conditions_gate_the_blocks() {
+    let notes = "1, 2, 3, 4";
+    let plain = transformed(notes, "rotate:0", 0).unwrap();
+    let played = |transforms, bars : std::ops::Range<u32>| bars
+        .filter(|&bar| transformed(notes, transforms, bar).unwrap() == plain)
+        .collect::<Vec<_>>();
+
+    assert_eq!(played("every:4", 0..9), vec![0, 4, 8]);
+    assert_eq!(played("when:bar%8==7", 0..16), vec![7, 15]);
+    assert_eq!(played("when:bar!=2", 0..4), vec![0, 1, 3]);
+    assert_eq!(transformed(notes, "every:4", 1).unwrap(), vec![]);
+    assert_eq!(played("when:bar%4!=0 transpose 12", 0..5), vec![0, 4]);
+    assert_eq!(transformed(notes, "when:bar%4!=0 transpose 12", 1).unwrap(), transformed(notes, "transpose:12", 0).unwrap());
+
+    let error = |transforms| transformed(notes, transforms, 0).unwrap_err();
+    assert_eq!(error("when"), "Missing parameter `condition` for when");
+    assert_eq!(error("when:1 rev"), "Parameter `condition` of when must be a condition, e.g. `bar%8==7`");
+    assert_eq!(error("when:bar%2==1 lp 800"), "when applies a transform, e.g. `when:bar%8==7 rev`");
+}
+
+#[test]
+fn random_notes_are_drawn_per_bar() {
+    let bars : Vec<Vec<(i32, f32, f32)>> = (0..64).map(|bar| transformed("C4?, 62?0, 64?1, rand(10, 20)", "", bar).unwrap()).collect();
+
+    assert!(bars.iter().all(|events| events.iter().all(|&(pitch, _, _)| pitch != 62)));
+    assert!(bars.iter().all(|events| events.iter().any(|&(pitch, start, _)| (pitch, start) == (64, 2.))));
+    assert!(bars.iter().all(|events| events.last().is_some_and(|&(pitch, _, _)| (10..=20).contains(&pitch))));
+    let with_c4 = bars.iter().filter(|events| events[0].0 == 60).count();
+    assert!(with_c4 > 16 && with_c4 < 48, "{}", with_c4);
+    assert_eq!(bars[5], transformed("C4?, 62?0, 64?1, rand(10, 20)", "", 5).unwrap());
+
+    let shuffled : Vec<Vec<(i32, f32, f32)>> = (0..8).map(|bar| transformed("1, 2, -, 3", "shuffle", bar).unwrap()).collect();
+    for events in &shuffled {
+        let mut pitches : Vec<i32> = events.iter().map(|&(pitch, _, _)| pitch).collect();
+        pitches.sort();
+        assert_eq!(pitches, vec![1, 2, 3]);
+        assert!(events.iter().any(|&(pitch, _, length)| (pitch, length) == (2, 2.)));
     }
-}
\ No newline at end of file
+    assert!(shuffled.iter().any(|events| events != &shuffled[0]));
+
+    assert_eq!(transformed("rand(12, 0), 1, 2, 3", "", 0).unwrap_err(), "Empty range : rand(12, 0)");
+    assert_eq!(transformed("1, 2, 3, 4", "shuffle:2", 0).unwrap_err(), "shuffle takes no arguments");
+}
+
+#[test]
+fn noise_follows_the_seed() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nnoise(C4, D4?, rand(60, 72), E4)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+    let bar = |seed, bar_index| build_bar(&axiom, &Tuning::default(), &mut PlaybackState {bar_index, seed, tail : vec![]}, &mut vec![]).unwrap();
+
+    assert_eq!(bar(1, 3), bar(1, 3));
+    assert_ne!(bar(1, 3), bar(2, 3));
+    assert_ne!(bar(1, 3), bar(1, 4));
+    assert!(bar(1, 3).iter().any(|&sample| sample != 0.));
+}
+
+#[test]
+fn bjorklund_sequences() {
+    let rhythm = |hits, steps| bjorklund(hits, steps).into_iter().map(|hit| if hit {'x'} else {'.'}).collect::<String>();
+
+    // Toussaint, "The Euclidean algorithm generates traditional musical rhythms",
+    // which lists a rotation of the rhythms with a single rest
+    assert_eq!(rhythm(1, 2), "x.");
+    assert_eq!(rhythm(1, 4), "x...");
+    assert_eq!(rhythm(2, 5), "x.x..");
+    assert_eq!(rhythm(3, 4), "xxx.");
+    assert_eq!(rhythm(3, 5), "x.x.x");
+    assert_eq!(rhythm(3, 7), "x.x.x..");
+    assert_eq!(rhythm(3, 8), "x..x..x.");
+    assert_eq!(rhythm(4, 7), "x.x.x.x");
+    assert_eq!(rhythm(4, 9), "x.x.x.x..");
+    assert_eq!(rhythm(4, 11), "x..x..x..x.");
+    assert_eq!(rhythm(4, 12), "x..x..x..x..");
+    assert_eq!(rhythm(5, 6), "xxxxx.");
+    assert_eq!(rhythm(5, 7), "x.xx.xx");
+    assert_eq!(rhythm(5, 8), "x.xx.xx.");
+    assert_eq!(rhythm(5, 9), "x.x.x.x.x");
+    assert_eq!(rhythm(5, 11), "x.x.x.x.x..");
+    assert_eq!(rhythm(5, 12), "x..x.x..x.x.");
+    assert_eq!(rhythm(5, 16), "x..x..x..x..x...");
+    assert_eq!(rhythm(7, 8), "xxxxxxx.");
+    assert_eq!(rhythm(7, 12), "x.xx.x.xx.x.");
+    assert_eq!(rhythm(7, 16), "x..x.x.x..x.x.x.");
+    assert_eq!(rhythm(9, 16), "x.xx.x.x.xx.x.x.");
+    assert_eq!(rhythm(11, 24), "x..x.x.x.x.x..x.x.x.x.x.");
+    assert_eq!(rhythm(13, 24), "x.xx.x.x.x.x.xx.x.x.x.x.");
+    assert_eq!(rhythm(0, 4), "....");
+    assert_eq!(rhythm(4, 4), "xxxx");
+}
+
+#[test]
+fn euclidean_rhythms_place_the_hits() {
+    assert_eq!(transformed("C2:euclid(3, 8)@4", "", 0).unwrap(), vec![(36, 0., 0.5), (36, 1.5, 0.5), (36, 3., 0.5)]);
+    assert_eq!(transformed("C2:euclid(3, 8, 1)@4", "", 0).unwrap(), vec![(36, 1., 0.5), (36, 2.5, 0.5), (36, 3.5, 0.5)]);
+    assert_eq!(transformed("C2:euclid(3, 8, -1)@4", "", 0).unwrap(), vec![(36, 0.5, 0.5), (36, 2., 0.5), (36, 3.5, 0.5)]);
+    assert_eq!(transformed("[C2 D2]:euclid(2, 4)@2, 1, 2", "transpose:12", 0).unwrap(),
+        vec![(48, 0., 0.25), (50, 0.25, 0.25), (48, 1., 0.25), (50, 1.25, 0.25), (13, 2., 1.), (14, 3., 1.)]);
+    assert_eq!(transformed("(C2 | D2):euclid(1, 2)@4", "", 1).unwrap(), vec![(38, 0., 2.)]);
+    assert_eq!(transformed("C2:euclid(0, 4)@4", "", 0).unwrap(), vec![]);
+
+    // The hits start on the samples of their steps
+    let (axiom, diagnostics) = parse("bpm 120\n4/4\nsimple(C4:euclid(1, 4, 2)@4)\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let buffer = build_buffer(&axiom.unwrap()).unwrap();
+    let onset = buffer.iter().position(|&sample| sample != 0.).unwrap();
+    assert_eq!(onset, crate::SAMPLE_RATE as usize + 1);
+}
+
+#[test]
+fn swing_shifts_the_onsets() {
+    let render = |header : &str, block : &str| {
+        let (axiom, diagnostics) = parse(format!("bpm 120\n4/4\n{}\n{}\n", header, block));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        build_buffer(&axiom.unwrap())
+    };
+    let onset = |header : &str, block : &str| render(header, block).unwrap().iter().position(|&sample| sample != 0.).unwrap();
+    // At 120 bpm a quarter note lasts half a second, the first sample of a note being silent
+    let sample = |quarters : f32| (quarters * 0.5 * crate::SAMPLE_RATE).round() as usize + 1;
+    let assert_near = |onset : usize, expected : usize| assert!(onset.abs_diff(expected) <= 1, "{} != {}", onset, expected);
+
+    assert_near(onset("", "simple([_ 60], _, _, _)"), sample(0.5));
+    assert_near(onset("swing 60%", "simple([_ 60], _, _, _)"), sample(0.6));
+    assert_near(onset("swing 60%", "simple(_, _, [_ 60], _)"), sample(2.6));
+    assert_near(onset("swing shuffle", "simple([_ 60], _, _, _)"), sample(2. / 3.));
+    // Only the notes on the steps move
+    assert_near(onset("swing 60%", "simple([_ 60 _ _], _, _, _)"), sample(0.25));
+    assert_near(onset("swing 60%", "simple([_ 60 _], _, _, _)"), sample(1. / 3.));
+    assert_near(onset("swing 60%", "simple(_, 60, _, _)"), sample(1.));
+    // The block's groove wins over the directive
+    assert_near(onset("swing 60%", "simple<swing:50%>([_ 60], _, _, _)"), sample(0.5));
+    assert_near(onset("", "simple<swing:mpc16>([_ 60 _ _], _, _, _)"), sample(0.29));
+    assert_near(onset("swing mpc16", "<swing:0.7>(\n\tsimple([_ 60], _, _, _)\n)"), sample(0.7));
+
+    let peak = |header : &str, block : &str| render(header, block).unwrap().iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+    let straight = peak("", "simple([_ 60 _ _], _, _, _)");
+    assert!((peak("swing mpc16", "simple([_ 60 _ _], _, _, _)") / straight - 0.7).abs() < 1e-3);
+    assert!((peak("swing mpc16", "simple([_ _ 60 _], _, _, _)") / straight - 0.85).abs() < 1e-3);
+
+    assert_eq!(render("", "simple<swing:90%>(1, 2, 3, 4)").unwrap_err(), "Swing is between 50% and 75%");
+    assert_eq!(render("", "simple<swing:funk>(1, 2, 3, 4)").unwrap_err(),
+        "Unknown groove : funk. Known grooves are mpc8, mpc16, shuffle and laid_back");
+}
+
+#[test]
+fn huge_weights_are_reported() {
+    let render = |notes : &str| {
+        let (axiom, diagnostics) = parse(format!("bpm 90\n4/4\nsimple({})\n", notes));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        build_buffer(&axiom.unwrap())
+    };
+
+    assert_eq!(render("1@4294967295, 2, 3, 4").unwrap_err(), "The weights of the notes add up to too much");
+    assert_eq!(render("[1@4294967295 2], 2, 3, 4").unwrap_err(), "The weights of the notes add up to too much");
+    assert!(render("[1@4294967294 2], 2, 3, 4").is_ok());
+}
+
+#[test]
+fn grooves_can_delay_notes_past_the_bar() {
+    let (axiom, diagnostics) = parse("bpm 90\n4/4\nswing laid_back\nsimple(1, 2, 3, [4@16777216 60])\n".to_string());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    let axiom = axiom.unwrap();
+
+    let mut state = PlaybackState::default();
+    build_bar(&axiom, &Tuning::default(), &mut state, &mut vec![]).unwrap();
+    // The last note starts in the next bar
+    assert!(state.tail.len() > (0.02 * 60. / 90. * crate::SAMPLE_RATE) as usize);
+    assert!(build_bar(&axiom, &Tuning::default(), &mut state, &mut vec![]).is_ok());
+}
@@ -5,6 +5,7 @@ use std::f32::consts::PI;
 
 pub trait FilterTrait {
     fn low_pass(&self, cutoff_freq : f32) -> Self;
+    fn resonant_low_pass(&self, cutoff_freq : f32, q : f32) -> Self;
     fn adsr(&self, duration : f32, attack : f32, decay : f32, sustain : f32, release : f32) -> Result<Self,String> where Self: Sized;
     fn echo(&self, delta : f32, loudness : f32) -> Self;
 }
@@ -24,6 +25,30 @@ impl FilterTrait for AudioBuffer {
         buffer
     }
 
+    // Second-order low-pass, resonating around the cut-off frequency when `q`
+    // is above 1/sqrt(2)
+    fn resonant_low_pass(&self, cutoff_freq : f32, q : f32) -> Self {
+        let mut buffer = Vec::with_capacity(self.len());
+
+        let w0 = 2.*PI*cutoff_freq / crate::SAMPLE_RATE;
+        let alpha = w0.sin() / (2.*q);
+        let cos = w0.cos();
+        let a0 = 1. + alpha;
+        let b0 = (1. - cos) / 2. / a0;
+        let b1 = (1. - cos) / a0;
+        let a1 = -2. * cos / a0;
+        let a2 = (1. - alpha) / a0;
+
+        let (mut x1, mut x2, mut y1, mut y2) = (0., 0., 0., 0.);
+        for &x in self {
+            let y = b0*x + b1*x1 + b0*x2 - a1*y1 - a2*y2;
+            buffer.push(y);
+            (x2, x1, y2, y1) = (x1, x, y1, y);
+        }
+
+        buffer
+    }
+
     // Attack decay sustain release
     fn adsr(&self,duration : f32, attack : f32, decay : f32, sustain : f32, release : f32) -> Result<Self,String> {
         
@@ -44,24 +69,24 @@ impl FilterTrait for AudioBuffer {
         let mut buffer = Vec::with_capacity(attack_s+decay_s+sustain_s+release_s);
 
         // Attack
-        for i in 0..attack_s {
-            buffer.push((i as f32)/(attack * crate::SAMPLE_RATE) * self[i]);
+        for (i, sample) in self[..attack_s].iter().enumerate() {
+            buffer.push((i as f32)/(attack * crate::SAMPLE_RATE) * sample);
         }
         // Decay
         let k = (1. - sustain) / (decay * crate::SAMPLE_RATE);
-        for i in 0..decay_s {
-            buffer.push((1. - k * (i as f32)) * self[i]);
+        for (i, sample) in self[attack_s..attack_s+decay_s].iter().enumerate() {
+            buffer.push((1. - k * (i as f32)) * sample);
         }
 
         // Sustain
-        for i in 0..sustain_s {
-            buffer.push( sustain * self[i]);
+        for sample in &self[attack_s+decay_s..duration_s] {
+            buffer.push( sustain * sample);
         }
 
         // Release
         let k = sustain / (release_s as f32);
-        for i in 0..release_s {
-            buffer.push((sustain - k * (i as f32)) * self[i]);
+        for (i, sample) in self[duration_s..duration_s+release_s].iter().enumerate() {
+            buffer.push((sustain - k * (i as f32)) * sample);
         }
 
         Ok(buffer)
@@ -81,3 +106,20 @@ impl FilterTrait for AudioBuffer {
     }
 
 }
+
+
+
+/* *************TESTS*************** */
+
+#[test]
+fn adsr_phases_scale_their_own_samples() {
+    let ramp : AudioBuffer = (0..44_000).map(|i| i as f32).collect();
+    let shaped = ramp.adsr(0.5, 0.1, 0.1, 0.5, 0.2).unwrap();
+
+    // Sustained at half the level of the sound at the same time, not of its
+    // beginning
+    assert!((10_000..22_000).all(|i| shaped[i] == 0.5 * ramp[i]));
+    // The release starts from the end of the note
+    assert_eq!(shaped[22_000], 0.5 * ramp[22_000]);
+    assert!(shaped[30_000] < 0.5 * ramp[30_000]);
+}
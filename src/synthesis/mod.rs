@@ -2,4 +2,5 @@ type AudioBuffer = Vec<f32>;
 
 pub mod oscillator;
 pub mod filters;
+pub mod tuning;
 pub mod buffer_builder;
\ No newline at end of file
@@ -1,46 +1,17 @@
-use std::f32::consts::PI;
 #[allow(unused_imports)]
 use micromath::F32Ext;
 use super::AudioBuffer;
-use fastrand;
+use fastrand::Rng;
 pub trait Oscillator {
     // OSCILLATORS
-    fn sin_wave(sample_size : usize, frequency : usize) -> Self;
-    fn sawtooth_wave(sample_size : usize, frequency : usize) -> Self;
-    fn triangle_wave(sample_size : usize, frequency : usize) -> Self;
-    fn square_wave(sample_size : usize, frequency : usize) -> Self;
-    fn square_wave_with_value(sample_size : usize, frequency : usize, value : f32) -> Self;
-    fn white_noise(sample_size: usize) -> Self;
+    fn square_wave(sample_size : usize, frequency : f32) -> Self;
+    /// Uniform noise between -1 and 1, drawn from `rng` so that it can be
+    /// reproduced
+    fn white_noise(sample_size: usize, rng : &mut Rng) -> Self;
 }
 
 impl Oscillator for AudioBuffer {
-    fn sin_wave(sample_size : usize, frequency : usize) -> Self {
-        let mut buffer = Vec::with_capacity(sample_size);
-
-        let f2pi = frequency as f32 * 2f32 * PI / 44_000f32;
-
-        for i in 0..sample_size {
-            buffer.push((i as f32 * f2pi).cos());
-        }
-
-        buffer
-    }
-
-    fn sawtooth_wave(sample_size : usize, frequency : usize) -> Self {
-        let frequency = frequency as f32;
-        let mut buffer = Vec::with_capacity(sample_size);
-
-        let period = 1. / frequency; 
-
-        for i in 0..sample_size {
-            buffer.push(2.*frequency*((i as f32 / crate::SAMPLE_RATE) % period));
-        }
-        
-        buffer
-    }
-
-    fn square_wave(sample_size : usize, frequency : usize) -> Self {
-        let frequency = frequency as f32;
+    fn square_wave(sample_size : usize, frequency : f32) -> Self {
         let mut buffer = Vec::with_capacity(sample_size);
 
         let half_period = 0.5 / frequency;
@@ -53,37 +24,11 @@ impl Oscillator for AudioBuffer {
         buffer
     }
 
-    fn square_wave_with_value(sample_size : usize, frequency : usize, value : f32) -> Self {
-        let frequency = frequency as f32;
-        let mut buffer = Vec::with_capacity(sample_size);
-        let neg_value = -value;
-
-        let half_period = 0.5 / frequency;
-        let period = 1. / frequency;
-
-        for i in 0..sample_size {
-            buffer.push(if ((i as f32 / crate::SAMPLE_RATE)%period) > half_period {value} else {neg_value});
-        }
-
-        buffer
-    }
-
-    fn triangle_wave(sample_size : usize, frequency : usize) -> Self {
-        let mut buffer = Vec::with_capacity(sample_size);
-        let square_buffer = AudioBuffer::square_wave_with_value(sample_size, frequency, 1./crate::SAMPLE_RATE);
-
-        for i in 0..sample_size {
-            buffer.push(buffer.last().unwrap_or(&0.) + square_buffer[i]);
-        }
-
-        buffer
-    }
-
-    fn white_noise(sample_size: usize) -> Self {
+    fn white_noise(sample_size: usize, rng : &mut Rng) -> Self {
         let mut buffer = Vec::with_capacity(sample_size);
 
         for _i in 0..sample_size {
-            buffer.push(fastrand::f32());
+            buffer.push(2. * rng.f32() - 1.);
         }
 
         buffer
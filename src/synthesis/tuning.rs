@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::code_parser::parser::Pitch;
+
+/// Standard frequency of `A4`, in Hz
+pub const A4_FREQUENCY : f32 = 440.;
+
+/// Largest scale or keyboard mapping accepted in Scala files
+const MAX_SCALA_SIZE : usize = 4096;
+
+/// Pitches of a scale, in cents above its tonic.
+/// The tonic itself is implicit, the last degree is the period (usually the octave).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub cents : Vec<f64>,
+}
+
+/// Which key plays which degree of the scale, as in Scala `.kbm` files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMapping {
+    /// Degree played by each key of a repeating pattern starting at
+    /// `middle_key`, `None` for silent keys. Empty to map every key to the
+    /// next degree.
+    pub mapping : Vec<Option<i64>>,
+    /// Keys that can be played
+    pub keys : RangeInclusive<i32>,
+    /// Key playing the tonic of the scale
+    pub middle_key : i32,
+    /// Key whose frequency is given
+    pub reference_key : i32,
+    pub reference_frequency : f64,
+    /// Degree reached when moving by a whole mapping pattern
+    pub octave_degree : i64,
+}
+
+/// Turns MIDI keys into frequencies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    pub scale : Scale,
+    pub mapping : KeyboardMapping,
+}
+
+impl Default for KeyboardMapping {
+    /// Every key plays the next degree, `C4` being the tonic and `A4` at 440 Hz
+    fn default() -> Self {
+        KeyboardMapping {
+            mapping : vec![],
+            keys : i32::MIN..=i32::MAX,
+            middle_key : 60,
+            reference_key : 69,
+            reference_frequency : A4_FREQUENCY as f64,
+            octave_degree : 0,
+        }
+    }
+}
+
+impl Default for Tuning {
+    /// Twelve-tone equal temperament, as in MIDI
+    fn default() -> Self {
+        Tuning::equal_temperament(12)
+    }
+}
+
+impl Tuning {
+    /// Octave divided in `divisions` equal steps, each key being a step.
+    pub fn equal_temperament(divisions : u32) -> Tuning {
+        let step = 1200. / divisions as f64;
+        Tuning {
+            scale : Scale { cents : (1..=divisions).map(|degree| degree as f64 * step).collect() },
+            mapping : KeyboardMapping::default(),
+        }
+    }
+
+    /// Scale given as frequency ratios to the tonic, the last one being the period.
+    pub fn from_ratios(ratios : &[(u32, u32)]) -> Tuning {
+        Tuning {
+            scale : Scale { cents : ratios.iter().map(|&(num, den)| ratio_to_cents(num as f64 / den as f64)).collect() },
+            mapping : KeyboardMapping::default(),
+        }
+    }
+
+    /// Reads a Scala scale file, and optionally a keyboard mapping file.
+    pub fn from_scala(scl : &str, kbm : Option<&str>) -> Result<Tuning, String> {
+        let scale = parse_scl(scl)?;
+        let mapping = match kbm {
+            Some(kbm) => parse_kbm(kbm)?,
+            None => KeyboardMapping::default(),
+        };
+        let tuning = Tuning { scale, mapping };
+        if tuning.degree(tuning.mapping.reference_key).is_none() {
+            return Err(format!("The reference key {} is not mapped", tuning.mapping.reference_key));
+        }
+
+        Ok(tuning)
+    }
+
+    /// Built-in tunings : `<n>edo`, `just` (5-limit) and `pythagorean`.
+    pub fn builtin(name : &str) -> Option<Tuning> {
+        match name {
+            "just" => Some(Tuning::from_ratios(&[
+                (16, 15), (9, 8), (6, 5), (5, 4), (4, 3), (45, 32),
+                (3, 2), (8, 5), (5, 3), (9, 5), (15, 8), (2, 1),
+            ])),
+            "pythagorean" => Some(Tuning::from_ratios(&[
+                (256, 243), (9, 8), (32, 27), (81, 64), (4, 3), (729, 512),
+                (3, 2), (128, 81), (27, 16), (16, 9), (243, 128), (2, 1),
+            ])),
+            _ => {
+                let divisions : u32 = name.strip_suffix("edo")?.parse().ok()?;
+                if divisions == 0 || divisions > 1200 {
+                    return None;
+                }
+                Some(Tuning::equal_temperament(divisions))
+            }
+        }
+    }
+
+    /// Looks `name` up in `custom` first, then in the built-in tunings.
+    pub fn resolve(name : &str, custom : &HashMap<String, Tuning>) -> Result<Tuning, String> {
+        custom.get(name).cloned()
+            .or_else(|| Tuning::builtin(name))
+            .ok_or_else(|| format!("Unknown tuning : {}", name))
+    }
+
+    /// Same tuning, with the reference key at `frequency` Hz.
+    pub fn with_reference(mut self, frequency : f32) -> Tuning {
+        self.mapping.reference_frequency = frequency as f64;
+        self
+    }
+
+    /// Frequency of `pitch` in Hz, `None` if the key is not mapped.
+    pub fn frequency(&self, pitch : Pitch) -> Option<f32> {
+        let cents = self.cents(self.degree(pitch.0)?) - self.cents(self.degree(self.mapping.reference_key)?);
+        Some((self.mapping.reference_frequency * 2f64.powf(cents / 1200.)) as f32)
+    }
+
+    /// Degree of the scale played by `key`, counted from the tonic.
+    fn degree(&self, key : i32) -> Option<i64> {
+        if !self.mapping.keys.contains(&key) {
+            return None;
+        }
+        let offset = key as i64 - self.mapping.middle_key as i64;
+        let size = self.mapping.mapping.len() as i64;
+        if size == 0 {
+            return Some(offset);
+        }
+
+        let degree = self.mapping.mapping[offset.rem_euclid(size) as usize]?;
+        Some(degree + offset.div_euclid(size) * self.mapping.octave_degree)
+    }
+
+    /// Pitch of a degree, in cents above the tonic.
+    fn cents(&self, degree : i64) -> f64 {
+        let size = self.scale.cents.len() as i64;
+        let period = self.scale.cents[size as usize - 1];
+        let index = degree.rem_euclid(size) as usize;
+        let within_period = if index == 0 { 0. } else { self.scale.cents[index - 1] };
+
+        degree.div_euclid(size) as f64 * period + within_period
+    }
+}
+
+fn ratio_to_cents(ratio : f64) -> f64 {
+    1200. * ratio.log2()
+}
+
+/// Lines of a Scala file, without the comments (starting with `!`).
+fn scala_lines(text : &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.starts_with('!'))
+}
+
+/// First word of a Scala line, the rest being comments.
+fn scala_field(line : Option<&str>, what : &str) -> Result<String, String> {
+    line.and_then(|line| line.split_whitespace().next())
+        .map(|field| field.to_string())
+        .ok_or_else(|| format!("Missing {} in Scala file", what))
+}
+
+fn scala_number<T : std::str::FromStr>(line : Option<&str>, what : &str) -> Result<T, String> {
+    let field = scala_field(line, what)?;
+    field.parse().map_err(|_| format!("Invalid {} in Scala file : {}", what, field))
+}
+
+/// Reads a `.scl` file: a description line, the number of degrees, then a
+/// degree per line, either in cents (with a dot) or as a ratio.
+fn parse_scl(text : &str) -> Result<Scale, String> {
+    let mut lines = scala_lines(text);
+    // Description, may be empty
+    lines.next().ok_or("Empty Scala file")?;
+    let mut lines = lines.filter(|line| !line.trim().is_empty());
+    let count : usize = scala_number(lines.next(), "number of notes")?;
+    if count == 0 {
+        return Err("A scale needs at least one degree".to_string());
+    }
+    if count > MAX_SCALA_SIZE {
+        return Err(format!("A scale cannot have more than {} degrees", MAX_SCALA_SIZE));
+    }
+
+    let mut cents = vec![];
+    for line in lines.take(count) {
+        let field = scala_field(Some(line), "scale degree")?;
+        let invalid = || format!("Invalid scale degree in Scala file : {}", field);
+        let value = if field.contains('.') {
+            field.parse().map_err(|_| invalid())?
+        }
+        else {
+            let (num, den) = field.split_once('/').unwrap_or((&field, "1"));
+            let num : f64 = num.parse().map_err(|_| invalid())?;
+            let den : f64 = den.parse().map_err(|_| invalid())?;
+            if num <= 0. || den <= 0. {
+                return Err(invalid());
+            }
+            ratio_to_cents(num / den)
+        };
+        cents.push(value);
+    }
+    if cents.len() < count {
+        return Err(format!("Missing scale degree in Scala file : expected {}, found {}", count, cents.len()));
+    }
+
+    Ok(Scale { cents })
+}
+
+/// Reads a `.kbm` file: map size, first and last keys, middle key, reference
+/// key and frequency, octave degree, then the degree of each key of the
+/// pattern (`x` for silent keys).
+fn parse_kbm(text : &str) -> Result<KeyboardMapping, String> {
+    let mut lines = scala_lines(text).filter(|line| !line.trim().is_empty());
+    let size : usize = scala_number(lines.next(), "map size")?;
+    let first_key : i32 = scala_number(lines.next(), "first key")?;
+    let last_key : i32 = scala_number(lines.next(), "last key")?;
+    let middle_key = scala_number(lines.next(), "middle key")?;
+    let reference_key = scala_number(lines.next(), "reference key")?;
+    let reference_frequency : f64 = scala_number(lines.next(), "reference frequency")?;
+    let octave_degree = scala_number(lines.next(), "octave degree")?;
+    if reference_frequency <= 0. {
+        return Err("The reference frequency must be positive".to_string());
+    }
+    if size > MAX_SCALA_SIZE {
+        return Err(format!("A keyboard mapping cannot have more than {} keys", MAX_SCALA_SIZE));
+    }
+
+    let mut mapping = vec![];
+    for line in lines.take(size) {
+        let field = scala_field(Some(line), "key mapping")?;
+        if field == "x" {
+            mapping.push(None);
+        }
+        else {
+            mapping.push(Some(field.parse().map_err(|_| format!("Invalid key mapping in Scala file : {}", field))?));
+        }
+    }
+    // Missing entries are silent keys
+    mapping.resize(size, None);
+
+    Ok(KeyboardMapping {
+        mapping,
+        keys : first_key..=last_key,
+        middle_key,
+        reference_key,
+        reference_frequency,
+        octave_degree,
+    })
+}
+
+
+
+/* *************TESTS*************** */
+
+
+#[cfg(test)]
+fn cents_between(frequency : f32, expected : f64) -> f64 {
+    ratio_to_cents(frequency as f64 / expected)
+}
+
+#[test]
+fn twelve_edo_is_midi_standard() {
+    let tuning = Tuning::default();
+    assert_eq!(tuning.frequency(Pitch(69)), Some(440.));
+    assert_eq!(tuning.frequency(Pitch(81)), Some(880.));
+    assert_eq!(tuning.frequency(Pitch(57)), Some(220.));
+    assert_eq!(tuning.clone().with_reference(432.).frequency(Pitch(69)), Some(432.));
+
+    for key in 0..=127 {
+        let expected = 440. * 2f64.powf((key - 69) as f64 / 12.);
+        let cents = cents_between(tuning.frequency(Pitch(key)).unwrap(), expected);
+        assert!(cents.abs() < 0.01, "key {} is {} cents off", key, cents);
+    }
+}
+
+#[test]
+fn equal_temperaments() {
+    let tuning = Tuning::builtin("31edo").unwrap();
+    let a4 = tuning.frequency(Pitch(69)).unwrap();
+
+    assert_eq!(a4, 440.);
+    assert!((cents_between(tuning.frequency(Pitch(70)).unwrap(), a4 as f64) - 1200. / 31.).abs() < 0.01);
+    assert!(cents_between(tuning.frequency(Pitch(69 + 31)).unwrap(), 880.).abs() < 0.01);
+    assert!(Tuning::builtin("0edo").is_none());
+    assert!(Tuning::builtin("edo").is_none());
+}
+
+#[test]
+fn just_intonation() {
+    let tuning = Tuning::builtin("just").unwrap();
+    let c4 = tuning.frequency(Pitch(60)).unwrap() as f64;
+
+    // A4 is the 5/3 of C4
+    assert!((c4 - 440. * 3. / 5.).abs() < 0.001);
+    assert!(cents_between(tuning.frequency(Pitch(64)).unwrap(), c4 * 5. / 4.).abs() < 0.01);
+    assert!(cents_between(tuning.frequency(Pitch(67)).unwrap(), c4 * 3. / 2.).abs() < 0.01);
+    assert!(cents_between(tuning.frequency(Pitch(72)).unwrap(), c4 * 2.).abs() < 0.01);
+}
+
+#[test]
+fn scala_files() {
+    let scl = "! meantone.scl\n!\nQuarter-comma meantone, partial\n 3\n!\n 193.157\n 5/4 ! major third\n 2\n";
+    let kbm = "! Only C, D and E\n3\n0\n127\n60\n60\n261.6256\n3\n0\n1\nx\n";
+
+    let tuning = Tuning::from_scala(scl, Some(kbm)).unwrap();
+    assert_eq!(tuning.scale.cents.len(), 3);
+    assert!((tuning.frequency(Pitch(60)).unwrap() - 261.6256).abs() < 0.001);
+    assert!((cents_between(tuning.frequency(Pitch(61)).unwrap(), 261.6256) - 193.157).abs() < 0.01);
+    assert_eq!(tuning.frequency(Pitch(62)), None);
+    assert!(cents_between(tuning.frequency(Pitch(63)).unwrap(), 2. * 261.6256).abs() < 0.01);
+    assert_eq!(tuning.frequency(Pitch(128)), None);
+
+    assert!(Tuning::from_scala("Nothing\n2\n100.0\n", None).is_err());
+    assert!(Tuning::from_scala("Bad\n1\nabc\n", None).is_err());
+    assert!(Tuning::from_scala(scl, Some("1\n0\n127\n60\n69\n440\n1\nx\n")).is_err());
+
+    // Sizes are checked before allocating anything
+    assert_eq!(Tuning::from_scala("desc\n99999999999999999\n100.0\n", None),
+               Err("A scale cannot have more than 4096 degrees".to_string()));
+    assert_eq!(Tuning::from_scala(scl, Some("999999999999999999\n0\n127\n60\n69\n440\n1\n0\n")),
+               Err("A keyboard mapping cannot have more than 4096 keys".to_string()));
+}
@@ -0,0 +1,216 @@
+use std::convert::TryInto;
+
+/// Intervals of a chord quality, in semitones above the root.
+///
+/// Qualities are written right after a note name, e.g. `C4maj` or `A3m7`.
+pub fn chord_intervals(quality : &str) -> Option<&'static [i32]> {
+    Some(match quality {
+        "maj" => &[0, 4, 7],
+        "m" | "min" => &[0, 3, 7],
+        "dim" => &[0, 3, 6],
+        "aug" => &[0, 4, 8],
+        "sus2" => &[0, 2, 7],
+        "sus4" => &[0, 5, 7],
+        "maj7" => &[0, 4, 7, 11],
+        "m7" | "min7" => &[0, 3, 7, 10],
+        "dom7" => &[0, 4, 7, 10],
+        "dim7" => &[0, 3, 6, 9],
+        "m7b5" => &[0, 3, 6, 10],
+        _ => return None,
+    })
+}
+
+/// Intervals of a scale or mode, in semitones above the tonic.
+pub fn scale_intervals(name : &str) -> Option<&'static [i32]> {
+    Some(match name {
+        "major" | "ionian" => &[0, 2, 4, 5, 7, 9, 11],
+        "dorian" => &[0, 2, 3, 5, 7, 9, 10],
+        "phrygian" => &[0, 1, 3, 5, 7, 8, 10],
+        "lydian" => &[0, 2, 4, 6, 7, 9, 11],
+        "mixolydian" => &[0, 2, 4, 5, 7, 9, 10],
+        "minor" | "aeolian" => &[0, 2, 3, 5, 7, 8, 10],
+        "locrian" => &[0, 1, 3, 5, 6, 8, 10],
+        "harmonic_minor" => &[0, 2, 3, 5, 7, 8, 11],
+        "melodic_minor" => &[0, 2, 3, 5, 7, 9, 11],
+        "pentatonic" | "major_pentatonic" => &[0, 2, 4, 7, 9],
+        "minor_pentatonic" => &[0, 3, 5, 7, 10],
+        "blues" => &[0, 3, 5, 6, 7, 10],
+        "whole_tone" => &[0, 2, 4, 6, 8, 10],
+        "chromatic" => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        _ => return None,
+    })
+}
+
+/// Chord written as a roman numeral, such as `ii7` or `V`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChordDegree {
+    /// Degree of the root in the scale, from 1
+    pub degree : u32,
+    /// Upper-case numerals have a major third, lower-case ones a minor third
+    pub major : bool,
+    /// Whether the seventh is added
+    pub seventh : bool,
+}
+
+/// Reads a roman numeral from `I` to `VII`, in upper or lower case,
+/// optionally followed by `7`.
+pub fn parse_numeral(text : &str) -> Option<ChordDegree> {
+    let (numeral, seventh) = match text.strip_suffix('7') {
+        Some(numeral) => (numeral, true),
+        None => (text, false),
+    };
+    let major = numeral.chars().all(|c| c.is_ascii_uppercase());
+    if !major && !numeral.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    let degree = match &numeral.to_ascii_lowercase()[..] {
+        "i" => 1,
+        "ii" => 2,
+        "iii" => 3,
+        "iv" => 4,
+        "v" => 5,
+        "vi" => 6,
+        "vii" => 7,
+        _ => return None,
+    };
+
+    Some(ChordDegree { degree, major, seventh })
+}
+
+/// Tonality the scale degrees refer to, declared with `key D minor`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Key {
+    /// Pitch class of the tonic, `0` for C
+    pub tonic : i32,
+    pub scale : &'static [i32],
+}
+
+impl Key {
+    /// MIDI key of a degree counted from 1, the first degree being in the
+    /// fourth octave. Degrees past the scale go up octaves, `None` past the
+    /// MIDI keys that fit in an `i32`.
+    pub fn degree(&self, degree : i64) -> Option<i32> {
+        let size = self.scale.len() as i64;
+        let index = degree.checked_sub(1)?;
+        let octave = index.div_euclid(size);
+        let key = octave.checked_mul(12)?.checked_add(60 + (self.tonic + self.scale[index.rem_euclid(size) as usize]) as i64)?;
+
+        key.try_into().ok()
+    }
+
+    /// MIDI keys of a chord, stacking thirds of the scale on its root. The
+    /// third is then made major or minor according to the numeral's case.
+    pub fn chord(&self, chord : ChordDegree) -> Option<Vec<i32>> {
+        let root = chord.degree as i64;
+        let mut keys = vec![
+            self.degree(root)?,
+            self.degree(root)?.checked_add(if chord.major {4} else {3})?,
+            self.degree(root + 4)?,
+        ];
+        if chord.seventh {
+            keys.push(self.degree(root + 6)?);
+        }
+
+        Some(keys)
+    }
+}
+
+/// Timing and accents of the steps of each quarter note, shifting the notes
+/// that start on a step. Declared with `swing 60%` or `swing mpc16`, or for a
+/// block with `<swing:60%>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Groove {
+    /// Steps in a quarter note
+    pub steps : u32,
+    /// Delay of each step from the straight grid, in steps, the pattern
+    /// repeating over the following steps
+    pub offsets : Vec<f32>,
+    /// Gain of the notes starting on each step
+    pub velocities : Vec<f32>,
+}
+
+impl Groove {
+    /// Eighth notes whose second one starts at `ratio` of the quarter note,
+    /// from `0.5`, straight, to `0.75`, dotted.
+    pub fn swing(ratio : f32) -> Option<Groove> {
+        if !(0.5..=0.75).contains(&ratio) {
+            return None;
+        }
+
+        Some(Groove { steps : 2, offsets : vec![0., 2. * ratio - 1.], velocities : vec![1., 1.] })
+    }
+
+    /// Start and gain of a note starting at `start`, in quarter notes. Notes
+    /// between the steps are left as they are.
+    pub fn shift(&self, start : f32) -> (f32, f32) {
+        let step = start * self.steps as f32;
+        if (step - step.round()).abs() > 1e-4 {
+            return (start, 1.);
+        }
+        let index = step.round() as usize;
+
+        (
+            start + self.offsets[index % self.offsets.len()] / self.steps as f32,
+            self.velocities[index % self.velocities.len()],
+        )
+    }
+}
+
+/// Groove templates in the manner of drum machines, named after their grid.
+pub fn groove_template(name : &str) -> Option<Groove> {
+    Some(match name {
+        "mpc8" => Groove { steps : 2, offsets : vec![0., 0.16], velocities : vec![1., 0.8] },
+        "mpc16" => Groove { steps : 4, offsets : vec![0., 0.16, 0., 0.16], velocities : vec![1., 0.7, 0.85, 0.7] },
+        "shuffle" => Groove { steps : 2, offsets : vec![0., 1. / 3.], velocities : vec![1., 0.75] },
+        "laid_back" => Groove { steps : 4, offsets : vec![0.08, 0.12, 0.08, 0.12], velocities : vec![0.9, 0.7, 0.8, 0.7] },
+        _ => return None,
+    })
+}
+
+
+
+/* *************TESTS*************** */
+
+
+#[test]
+fn degrees_of_keys() {
+    let d_minor = Key { tonic : 2, scale : scale_intervals("minor").unwrap() };
+
+    assert_eq!(d_minor.degree(1), Some(62));
+    assert_eq!(d_minor.degree(3), Some(65));
+    assert_eq!(d_minor.degree(8), Some(74));
+    assert_eq!(d_minor.degree(0), Some(60));
+    assert_eq!(d_minor.degree(-6), Some(50));
+    assert_eq!(d_minor.degree(u32::MAX as i64), None);
+}
+
+#[test]
+fn chords_of_keys() {
+    let c_major = Key { tonic : 0, scale : scale_intervals("major").unwrap() };
+    let a_minor = Key { tonic : 9, scale : scale_intervals("minor").unwrap() };
+
+    assert_eq!(c_major.chord(parse_numeral("I").unwrap()), Some(vec![60, 64, 67]));
+    assert_eq!(c_major.chord(parse_numeral("ii7").unwrap()), Some(vec![62, 65, 69, 72]));
+    assert_eq!(c_major.chord(parse_numeral("V7").unwrap()), Some(vec![67, 71, 74, 77]));
+    assert_eq!(c_major.chord(parse_numeral("vii").unwrap()), Some(vec![71, 74, 77]));
+    assert_eq!(a_minor.chord(parse_numeral("V").unwrap()), Some(vec![76, 80, 83]));
+    assert_eq!(a_minor.chord(parse_numeral("iv").unwrap()), Some(vec![74, 77, 81]));
+    assert_eq!(parse_numeral("Vi"), None);
+    assert_eq!(parse_numeral("viii"), None);
+}
+
+#[test]
+fn grooves_shift_the_steps() {
+    let swing = Groove::swing(0.6).unwrap();
+    assert_eq!(swing.shift(0.), (0., 1.));
+    assert!((swing.shift(2.5).0 - 2.6).abs() < 1e-5);
+    assert_eq!(swing.shift(0.25), (0.25, 1.));
+    assert_eq!(Groove::swing(0.5).unwrap().shift(1.5), (1.5, 1.));
+    assert_eq!(Groove::swing(0.8), None);
+
+    let mpc16 = groove_template("mpc16").unwrap();
+    assert!((mpc16.shift(1.25).0 - 1.29).abs() < 1e-5);
+    assert_eq!(mpc16.shift(1.25).1, 0.7);
+    assert_eq!(mpc16.shift(1.5), (1.5, 0.85));
+    assert_eq!(groove_template("funk"), None);
+}
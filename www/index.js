@@ -1,4 +1,3 @@
-import { memory } from "code-musique/code_musique_bg.wasm";
 import * as wasm from "code-musique";
 
 const codeArea = document.getElementById("codeArea");
@@ -7,6 +6,7 @@ const outputArea = document.getElementById("outputArea");
 const downloadButton = document.getElementById("downloadButton");
 const uploadZone = document.getElementById("uploadZone");
 const stopButton = document.getElementById("stopButton");
+const barInfo = document.getElementById("barInfo");
 
 const PREVISION_MS = 200;
 const SAMPLING_RATE = 44000;
@@ -18,14 +18,36 @@ let barStartTime = 0;
 
 
 let audioContext = new AudioContext();
+// Moteur conservant son état d'une mesure à l'autre
+let engine = new wasm.Engine();
 
 codeArea.addEventListener("input", (event)=> {
-    if (!isPlaying) {
+    // Ce qui a pu être compilé est joué dès la prochaine mesure
+    showDiagnostics(engine.update_code(codeArea.value));
+    if (!isPlaying && engine.has_code) {
         isPlaying = true;
         parseAndPlay();
     }
 });
 
+// Affiche les erreurs avec leur position dans le code
+function showDiagnostics(diagnostics) {
+    outputArea.replaceChildren(...diagnostics.map((diagnostic) => {
+        const line = document.createElement("p");
+        const span = diagnostic.span;
+        line.className = diagnostic.severity === wasm.Severity.Error ? "error" : "warning";
+        line.textContent = `Ligne ${span.line}, colonne ${span.column} : ${diagnostic.message}`;
+        if (diagnostic.hint) {
+            line.title = diagnostic.hint;
+        }
+        line.addEventListener("click", () => {
+            codeArea.focus();
+            codeArea.setSelectionRange(span.start, Math.max(span.end, span.start + 1));
+        });
+        return line;
+    }));
+}
+
 function parseAndPlay() {
     let bufferSourceNode = parse();
     bufferSourceNode.start(barStartTime);
@@ -40,25 +62,25 @@ document.addEventListener("prepareNextBar",(event) => {
 function parse() {
     let bufferSourceNode = audioContext.createBufferSource();
     bufferSourceNode.connect(audioContext.destination);
-    let result = wasm.compile(codeArea.value);
-    let resultData;
-    try {
-        resultData = new Float32Array(memory.buffer, result.pointer, result.size);
-    }
-    catch(error) {
-        // TODO
-    }
+    // Position dans le morceau, qui reprend au début une fois fini
+    const section = engine.section;
+    barInfo.textContent = `Mesure ${engine.bar_index % engine.song_length + 1}/${engine.song_length}`
+        + (section ? `, section ${section}` : "");
+    let renderedBar = engine.render_next_bar();
     let buffer = new AudioBuffer({
-        length: result.size, 
+        length: renderedBar.size, 
         numberOfChannels: 1, 
         sampleRate: SAMPLING_RATE,
     });
-    buffer.getChannelData(0).set(resultData);
+    // Copie des échantillons, la mesure peut ensuite être libérée
+    buffer.getChannelData(0).set(renderedBar.samples());
+    // Durée exacte, le nombre d'échantillons étant arrondi
+    const barDuration = renderedBar.duration;
+    renderedBar.free();
     bufferSourceNode.buffer = buffer;
     
 
     // Plan next bar
-    const barDuration = result.size/SAMPLING_RATE;
     const event = new Event("prepareNextBar");
     barStartTime = barStartTime+barDuration;
     setTimeout(() => document.dispatchEvent(event), 1000*barDuration-PREVISION_MS);